@@ -43,6 +43,7 @@
               # This config is only used until the first nixos-rebuild. For the config installed to /etc/nixos/configuration.nix, see modules/build-tarball.nix
 
               wsl.enable = true;
+              wsl.shim.firstBoot.enable = true;
 
               programs.bash.loginShellInit = "nixos-wsl-welcome";
 
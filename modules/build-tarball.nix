@@ -73,6 +73,23 @@ in
 
   # These options make no sense without the wsl-distro module anyway
   config = mkIf config.wsl.enable {
+    # Exports the running system without going through the tarball builder
+    environment.systemPackages = [
+      (pkgs.runCommand "nixos-wsl-export" { } ''
+        mkdir -p $out/bin
+        ln -s ${config.system.build.nativeUtils}/bin/nixos-wsl-export $out/bin/
+      '')
+    ];
+
+    # Left in exported tarballs, whose store paths aren't in the Nix database yet.
+    # The tarball builder doesn't need this, nixos-install registers them itself.
+    system.activationScripts.nixPathRegistration = stringAfter [ "specialfs" ] ''
+      if [ -f /nix-path-registration ]; then
+        echo "registering the store paths of the exported system..."
+        ${config.nix.package.out}/bin/nix-store --load-db < /nix-path-registration && rm /nix-path-registration
+      fi
+    '';
+
     system.build.tarballBuilder = pkgs.writeShellApplication {
       name = "nixos-wsl-tarball-builder";
 
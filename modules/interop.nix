@@ -1,4 +1,4 @@
-{ lib, config, ... }:
+{ lib, config, pkgs, ... }:
 
 with builtins; with lib;
 {
@@ -14,6 +14,42 @@ with builtins; with lib;
       default = true;
       description = "Include Windows PATH in WSL PATH";
     };
+
+    stableSocket = mkOption {
+      type = bool;
+      default = false;
+      description = ''
+        Keep `/run/nixos-wsl/interop` pointed at a working interop socket and use it as `WSL_INTEROP` in new shells.
+        This fixes "Unable to start interop" errors in tmux sessions and services that outlive the WSL session they were started from.
+      '';
+    };
+
+    pathFilter = {
+      allow = mkOption {
+        type = listOf str;
+        default = [ ];
+        example = [ "/mnt/c/Windows/*" "/mnt/c/Users/*/AppData/Local/Programs/Microsoft VS Code/bin" ];
+        description = ''
+          Windows PATH entries to include in WSL PATH, if `includePath` is enabled. Empty means all of them.
+          `*` matches anything but `/`, and case doesn't matter. All entries stay available in `$WSLPATH`.
+        '';
+      };
+      deny = mkOption {
+        type = listOf str;
+        default = [ ];
+        example = [ "/mnt/c/Windows/System32/OpenSSH" ];
+        description = "Windows PATH entries to leave out of WSL PATH, even if they match `allow`. Uses the same patterns.";
+      };
+    };
+
+    nativeWslpath = mkOption {
+      type = bool;
+      default = true;
+      description = ''
+        Install `nixos-wsl-path`, which converts between Windows and WSL paths like `wslpath` does (`-u`, `-w`, `-m` and `-a` are supported).
+        It works from the mount table alone, so it can be used in activation scripts and before interop is available.
+      '';
+    };
   };
 
   config =
@@ -22,21 +58,69 @@ with builtins; with lib;
     in
     mkIf config.wsl.enable {
 
-      boot.binfmt.registrations = mkIf cfg.register {
-        WSLInterop = {
-          magicOrExtension = "MZ";
-          fixBinary = true;
-          wrapInterpreterInShell = false;
-          interpreter = "/init";
-          preserveArgvZero = true;
+      # Read by split-path in every new shell, see wsl.interop.pathFilter
+      environment.etc."nixos-wsl/path-filter" = mkIf (cfg.pathFilter.allow != [ ] || cfg.pathFilter.deny != [ ]) {
+        text = concatMapStrings (pattern: "allow ${pattern}\n") cfg.pathFilter.allow
+          + concatMapStrings (pattern: "deny ${pattern}\n") cfg.pathFilter.deny;
+      };
+
+      # systemd-binfmt flushes all registrations when it starts, so this has to be redone every time it runs
+      systemd.services.wsl-binfmt-interop = mkIf cfg.register {
+        description = "Register the WSL interop binfmt_misc handler";
+        after = [ "systemd-binfmt.service" "proc-sys-fs-binfmt_misc.mount" ];
+        partOf = [ "systemd-binfmt.service" ];
+        wantedBy = [ "sysinit.target" "systemd-binfmt.service" ];
+        before = [ "sysinit.target" ];
+        unitConfig.DefaultDependencies = false;
+        serviceConfig = {
+          Type = "oneshot";
+          RemainAfterExit = true;
+          ExecStart = "${config.system.build.nativeUtils}/bin/nixos-wsl-binfmt";
+        };
+      };
+
+      systemd.services.wsl-interop-socket = mkIf cfg.stableSocket {
+        description = "Point /run/nixos-wsl/interop at a working interop socket";
+        serviceConfig = {
+          Type = "oneshot";
+          ExecStart = "${config.system.build.nativeUtils}/bin/nixos-wsl-interop-socket";
         };
       };
+      # Sessions coming and going add and remove sockets, the timer catches sockets that went stale without that.
+      # The link lives outside /run/WSL, otherwise updating it would trigger the path unit again.
+      systemd.paths.wsl-interop-socket = mkIf cfg.stableSocket {
+        wantedBy = [ "multi-user.target" ];
+        pathConfig.PathChanged = "/run/WSL";
+      };
+      systemd.timers.wsl-interop-socket = mkIf cfg.stableSocket {
+        wantedBy = [ "timers.target" ];
+        timerConfig = {
+          OnActiveSec = "0";
+          OnUnitActiveSec = "1min";
+        };
+      };
+      environment.extraInit = mkIf cfg.stableSocket ''
+        if [ -S /run/nixos-wsl/interop ]; then
+          export WSL_INTEROP=/run/nixos-wsl/interop
+        fi
+      '';
+
+      environment.systemPackages =
+        let
+          link = name: pkgs.runCommand name { } ''
+            mkdir -p $out/bin
+            ln -s ${config.system.build.nativeUtils}/bin/${name} $out/bin/
+          '';
+        in
+        optional cfg.nativeWslpath (link "nixos-wsl-path")
+        # Reads and changes the settings of the distro in the Windows registry
+        ++ [ (link "nixos-wsl-settings") ];
 
       warnings =
         let
           registrations = config.boot.binfmt.registrations;
         in
-        optional (!(registrations ? WSLInterop) && (length (attrNames config.boot.binfmt.registrations)) != 0) "Having any binfmt registrations without re-registering WSLInterop (wsl.interop.register) will break running .exe files from WSL2";
+        optional (!cfg.register && !(registrations ? WSLInterop) && (length (attrNames config.boot.binfmt.registrations)) != 0) "Having any binfmt registrations without re-registering WSLInterop (wsl.interop.register) will break running .exe files from WSL2";
     };
 
 
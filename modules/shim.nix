@@ -0,0 +1,259 @@
+{ config, lib, pkgs, ... }:
+
+with lib;
+let
+  cfg = config.wsl.shim;
+
+  # Keep in sync with NEXT_BOOT_POINTER in utils/src/generations.rs
+  nextBootPointer = "/nix/var/nix/gcroots/nixos-wsl/next-boot";
+
+  stage = pkgs.writeShellApplication {
+    name = "nixos-wsl-stage";
+    runtimeInputs = [ pkgs.coreutils ];
+    text = ''
+      usage() {
+        echo "Usage: $0 GENERATION|PATH"
+        echo "       $0 --clear"
+        echo
+        echo "Boot the given system generation (a number or the path of a built system) once,"
+        echo "the next time the distro is started. The system profile is left untouched."
+        exit 1
+      }
+
+      if ! [ $EUID -eq 0 ]; then
+        echo "This script must be run as root!"
+        exit 1
+      fi
+
+      case "''${1:-}" in
+        "" | -h | --help)
+          usage
+          ;;
+        --clear)
+          rm -f ${nextBootPointer}
+          exit 0
+          ;;
+      esac
+
+      if [[ "$1" =~ ^[0-9]+$ ]]; then
+        system="/nix/var/nix/profiles/system-$1-link"
+      else
+        system="$1"
+      fi
+      system="$(readlink -f "$system")"
+
+      if ! [ -x "$system/activate" ] || ! [ -x "$system/systemd/lib/systemd/systemd" ]; then
+        echo "$1 is not a NixOS system"
+        exit 1
+      fi
+
+      mkdir -p "$(dirname ${nextBootPointer})"
+      # The pointer is also a GC root, so the system stays around until it is booted
+      ln -sfn "$system" ${nextBootPointer}
+      echo "$system will be booted the next time the distro is started"
+    '';
+  };
+
+  # Keep in sync with the --pending default in utils/src/first_boot.rs
+  firstBootPending = "/var/lib/nixos-wsl/first-boot";
+
+  firstBoot = pkgs.writeShellScript "nixos-wsl-first-boot" ''
+    exec ${config.system.build.nativeUtils}/bin/nixos-wsl-first-boot --automount-root=${config.wsl.wslConf.automount.root} "$@"
+  '';
+
+  # Only the boot menu out of the utils, the rest isn't meant to be run by hand
+  bootMenu = pkgs.runCommand "nixos-wsl-boot-menu" { } ''
+    mkdir -p $out/bin
+    ln -s ${config.system.build.nativeUtils}/bin/nixos-wsl-boot-menu $out/bin/
+  '';
+
+  # Unset options are left out, so the shim falls back to its built-in defaults
+  settings = filterAttrs (_: section: section != { }) {
+    steps = genAttrs cfg.disabledSteps (_: false);
+    step-policy = cfg.stepPolicy;
+    dev-shm = filterAttrs (_: v: v != null) {
+      inherit (cfg.devShm) size mode huge;
+    };
+    hugepages = optionalAttrs (cfg.hugepages.count > 0) (filterAttrs (_: v: v != null) {
+      pages = cfg.hugepages.count;
+      page-size = cfg.hugepages.pageSize;
+      mount-point = cfg.hugepages.mountPoint;
+    });
+    activation = {
+      inherit (cfg.activation) timeout;
+      on-timeout = cfg.activation.onTimeout;
+      keep-logs = cfg.activation.keepLogs;
+    };
+    boot-menu = optionalAttrs cfg.bootMenu.enable {
+      path = "${config.system.build.nativeUtils}/bin/nixos-wsl-boot-menu";
+      inherit (cfg.bootMenu) timeout;
+    };
+    first-boot = optionalAttrs cfg.firstBoot.enable {
+      path = "${firstBoot}";
+    };
+    systemd = filterAttrs (_: v: v != null && v != "") {
+      inherit (cfg.systemd) path;
+      extra-args = concatStringsSep " " cfg.systemd.extraArgs;
+    };
+  };
+in
+{
+  options.wsl.shim = with types; {
+    disabledSteps = mkOption {
+      type = listOf (enum [ "hugepages" "dev-shm" "root-shared" "store-ro" "activation" "first-boot" ]);
+      default = [ ];
+      example = [ "store-ro" ];
+      description = ''
+        Boot steps the /sbin/init shim should skip. Run `systemd-shim --list-steps` to see what each step does.
+        Disabling steps can leave the system in a broken state, use with caution!
+      '';
+    };
+    stepPolicy = mkOption {
+      type = attrsOf (enum [ "skip" "retry" "fatal" ]);
+      default = { };
+      example = { store-ro = "fatal"; activation = "skip"; };
+      description = ''
+        What the /sbin/init shim does when a boot step fails, instead of the step's built-in behaviour.
+        `skip` tries once and boots anyway, `retry` retries transient errors a few times before booting anyway,
+        and `fatal` aborts the boot, or falls back to an older generation if activation fails.
+      '';
+    };
+    copyToSbin = mkOption {
+      type = bool;
+      default = false;
+      description = ''
+        Copy a statically linked build of the /sbin/init shim and busybox to /sbin, instead of linking into the Nix store.
+        This way WSL can still start the shim (and the shim can still spawn a rescue shell) when the store is corrupted or cannot be mounted.
+        Requires building the utils against musl, which may not be available from the binary cache.
+      '';
+    };
+    devShm = {
+      size = mkOption {
+        type = nullOr (strMatching "[0-9]+[kKmMgG%]?");
+        default = null;
+        example = "8G";
+        description = "Size of the /dev/shm tmpfs, either in bytes (with an optional k, m or g suffix) or as a percentage of RAM. The kernel default is 50%.";
+      };
+      mode = mkOption {
+        type = nullOr (strMatching "[0-7]{3,4}");
+        default = null;
+        example = "1777";
+        description = "Octal permissions of /dev/shm";
+      };
+      huge = mkOption {
+        type = nullOr (enum [ "never" "always" "within_size" "advise" ]);
+        default = null;
+        example = "within_size";
+        description = "Transparent hugepage policy for /dev/shm, see tmpfs(5)";
+      };
+    };
+    hugepages = {
+      count = mkOption {
+        type = ints.unsigned;
+        default = 0;
+        example = 1024;
+        description = ''
+          Number of hugepages to reserve early during boot, before memory gets fragmented.
+          The kernel might reserve fewer pages if it cannot find enough contiguous memory, which is logged to dmesg.
+        '';
+      };
+      pageSize = mkOption {
+        type = nullOr (strMatching "[0-9]+[kKmMgG]");
+        default = null;
+        example = "1G";
+        description = "Size of the reserved hugepages. Defaults to the kernel's default hugepage size (usually 2M).";
+      };
+      mountPoint = mkOption {
+        type = str;
+        default = "/dev/hugepages";
+        description = "Where to mount hugetlbfs for the reserved pages";
+      };
+    };
+    activation = {
+      timeout = mkOption {
+        type = ints.unsigned;
+        default = 300;
+        description = "Seconds the activation script may run before the shim stops it. 0 waits forever.";
+      };
+      onTimeout = mkOption {
+        type = enum [ "continue" "recover" ];
+        default = "recover";
+        description = ''
+          What to do when activation times out.
+          `recover` treats it like a failed activation and falls back to an older generation,
+          `continue` starts systemd anyway, with a possibly half activated system.
+        '';
+      };
+      keepLogs = mkOption {
+        type = ints.unsigned;
+        default = 10;
+        description = "Number of activation logs (`/var/log/nixos-wsl/activation-<time>.log`) to keep. 0 doesn't save activation output to a file.";
+      };
+    };
+    bootMenu = {
+      enable = mkEnableOption "the boot menu, which lets you pick the generation to boot on the console. It is shown when `nixos-wsl.boot-menu` is on the kernel command line, or once after creating /etc/nixos-wsl/boot-menu";
+      timeout = mkOption {
+        type = ints.unsigned;
+        default = 10;
+        description = "Seconds the boot menu waits for a choice before booting the default generation. 0 waits forever.";
+      };
+    };
+    firstBoot.enable = mkEnableOption ''
+      provisioning a freshly imported distro from the `.nixos-wsl` directory in the home directory of a Windows user.
+      It can create the default user, replace /etc/nixos or build a flake, see "Install unattended" in the docs.
+      This runs once, until /var/lib/nixos-wsl/provisioned exists
+    '';
+    systemd = {
+      path = mkOption {
+        type = nullOr path;
+        default = null;
+        example = literalExpression ''"''${pkgs.systemd}/lib/systemd/systemd"'';
+        description = ''
+          systemd executable the shim starts after the boot steps, instead of the one from the booted generation.
+          Since the shim reads its config before activation, this always comes from the previously activated generation.
+        '';
+      };
+      extraArgs = mkOption {
+        type = listOf (strMatching "[^[:space:]]+");
+        default = [ ];
+        example = [ "--log-level=debug" ];
+        description = "Extra arguments passed to systemd when the shim starts it";
+      };
+    };
+  };
+
+  config = mkIf config.wsl.enable {
+    environment.systemPackages = [ stage ] ++ optional cfg.bootMenu.enable bootMenu;
+
+    # Read by the /sbin/init shim before activation, so changes apply the next time the distro is started
+    environment.etc."nixos-wsl/shim.conf".text = generators.toINI { } settings;
+
+    # The shim creates the user and copies the config before systemd starts, this does what needs the network and interop
+    systemd.services.nixos-wsl-first-boot = mkIf cfg.firstBoot.enable {
+      description = "Finish provisioning the distro";
+      wantedBy = [ "multi-user.target" ];
+      wants = [ "network-online.target" ];
+      after = [ "network-online.target" ];
+      unitConfig.ConditionPathExists = firstBootPending;
+      path = [ config.nix.package pkgs.git config.system.build.nixos-rebuild ];
+      environment.NIX_PATH = concatStringsSep ":" config.nix.nixPath;
+      serviceConfig.Type = "oneshot";
+      script = ''
+        . ${firstBootPending}
+        if [ -n "''${DEFAULT_UID:-}" ]; then
+          ${config.system.build.nativeUtils}/bin/nixos-wsl-settings ''${DISTRO:+--distro="$DISTRO"} \
+            --reg=${config.wsl.wslConf.automount.root}/c/Windows/System32/reg.exe \
+            set default-uid "$DEFAULT_UID" ||
+            echo "Could not set the default UID in the registry, set it with nixos-wsl-settings"
+        fi
+        if [ -n "''${FLAKE:-}" ]; then
+          nixos-rebuild boot --option experimental-features "nix-command flakes" --flake "$FLAKE"
+        elif [ -n "''${REBUILD:-}" ]; then
+          nixos-rebuild boot
+        fi
+        rm ${firstBootPending}
+        echo "Provisioning is done, it applies after wsl.exe --terminate"
+      '';
+    };
+  };
+}
@@ -7,7 +7,34 @@
     (lib.mkRemovedOptionModule [ "wsl" "nativeSystemd" ] "Native systemd is now always enabled as support for syschdemd has been removed")
   ];
 
+  options.wsl.firewall = {
+    networkingMode = lib.mkOption {
+      type = lib.types.enum [ "nat" "mirrored" ];
+      default = "nat";
+      description = ''
+        The `networkingMode` set in the host's `.wslconfig`.
+        The distro can't see which mode WSL runs in, so it has to be repeated here to get the matching firewall rules.
+      '';
+    };
+
+    enable = lib.mkOption {
+      type = lib.types.bool;
+      default = config.wsl.firewall.networkingMode == "mirrored";
+      defaultText = lib.literalExpression ''config.wsl.firewall.networkingMode == "mirrored"'';
+      description = ''
+        Whether to run the NixOS firewall (`networking.firewall`).
+        With WSL's default NAT networking the distro can't be reached from the LAN, so the firewall is off by default.
+        With `networkingMode=mirrored` in `.wslconfig` however, services listening on all interfaces are exposed to the host's network, so it is on.
+        It blocks inbound connections, except for established ones and ports opened with options like `networking.firewall.allowedTCPPorts`.
+      '';
+    };
+  };
+
   config = lib.mkIf config.wsl.enable {
+    # In mirrored mode, connections to localhost on Windows come in through loopback0. Blocking them would break localhost forwarding.
+    # With NAT, WSL forwards them from inside the distro, so they already come from lo.
+    networking.firewall.trustedInterfaces = lib.mkIf (config.wsl.firewall.enable && config.wsl.firewall.networkingMode == "mirrored") [ "loopback0" ];
+
     # useful for usbip but adds a dependency on various firmwares which are combined over 300 MB big
     services.udev.enable = lib.mkDefault false;
 
@@ -15,7 +42,7 @@
       # systemd-oomd requires cgroup pressure info which WSL doesn't have
       oomd.enable = false;
       # Disable systemd units that don't make sense on WSL
-      services.firewall.enable = false;
+      services.firewall.enable = config.wsl.firewall.enable;
 
       # Don't allow emergency mode, because we don't have a console.
       enableEmergencyMode = false;
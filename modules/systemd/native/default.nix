@@ -16,7 +16,8 @@ with lib; {
     in
     mkIf (cfg.enable) {
 
-      system.build.nativeUtils = pkgs.callPackage ../../../utils { };
+      system.build.nativeUtils = pkgs.callPackage ../../../utils { inherit (cfg.version) release rev; };
+      system.build.staticNativeUtils = pkgs.pkgsStatic.callPackage ../../../utils { inherit (cfg.version) release rev; };
 
       wsl = {
         binShPkg = bashWrapper;
@@ -51,17 +52,29 @@ with lib; {
             fi
           ''
         );
-        shimSystemd = stringAfter [ "createSbin" ] ''
-          echo "setting up /sbin/init shim..."
-          ln -sf ${config.system.build.nativeUtils}/bin/systemd-shim /sbin/init
-        '';
+        shimSystemd = stringAfter [ "createSbin" ] (
+          if cfg.shim.copyToSbin
+          then ''
+            echo "setting up /sbin/init shim..."
+            # copy through a temporary file, so there is always a working /sbin/init
+            install -m755 ${config.system.build.staticNativeUtils}/bin/systemd-shim /sbin/.init.tmp
+            mv -f /sbin/.init.tmp /sbin/init
+            install -m755 ${pkgs.pkgsStatic.busybox}/bin/busybox /sbin/.nixos-wsl-busybox.tmp
+            mv -f /sbin/.nixos-wsl-busybox.tmp /sbin/nixos-wsl-busybox
+          ''
+          else ''
+            echo "setting up /sbin/init shim..."
+            ln -sf ${config.system.build.nativeUtils}/bin/systemd-shim /sbin/init
+            rm -f /sbin/nixos-wsl-busybox
+          ''
+        );
       };
 
       environment = {
         # preserve $PATH from parent
         variables.PATH = [ "$PATH" ];
         extraInit = ''
-          eval $(${config.system.build.nativeUtils}/bin/split-path --automount-root="${cfg.wslConf.automount.root}" ${lib.optionalString cfg.interop.includePath "--include-interop"})
+          eval $(${config.system.build.nativeUtils}/bin/split-path --automount-root="${cfg.wslConf.automount.root}" ${lib.optionalString cfg.interop.includePath "--include-interop"} ${lib.optionalString (cfg.interop.pathFilter.allow != [ ] || cfg.interop.pathFilter.deny != [ ]) "--filter=/etc/nixos-wsl/path-filter"})
         '';
       };
     };
@@ -48,7 +48,8 @@ with lib; {
             };
             options = mkOption {
               type = types.commas; # comma-separated strings
-              default = "metadata,uid=1000,gid=100";
+              default = "metadata,uid=${toString config.users.users.${config.wsl.defaultUser}.uid},gid=100";
+              defaultText = literalExpression ''"metadata,uid=''${toString config.users.users.''${config.wsl.defaultUser}.uid},gid=100"'';
               description = "Comma-separated list of mount options that should be used for mounting windows drives.";
             };
           };
@@ -105,17 +106,66 @@ with lib; {
       };
     };
 
-  config = mkIf config.wsl.enable {
+  config =
+    let
+      settings = filterAttrsRecursive (_: v: v != "") config.wsl.wslConf;
+
+      # The keys documented at <https://learn.microsoft.com/en-us/windows/wsl/wsl-config#configuration-settings-for-wslconf>,
+      # and `automount.ldconfig`, which WSL reads as well
+      knownKeys = {
+        automount = [ "enabled" "ldconfig" "mountFsTab" "root" "options" ];
+        network = [ "generateHosts" "generateResolvConf" "hostname" ];
+        interop = [ "enabled" "appendWindowsPath" ];
+        user = [ "default" ];
+        boot = [ "systemd" "command" "protectBinfmt" ];
+        gpu = [ "enabled" ];
+        time = [ "useWindowsTimezone" ];
+      };
+
+      # The known name `name` is probably a typo of
+      likelyTypo = name: known: findFirst
+        (candidate: candidate != name && strings.levenshteinAtMost 2 (toLower candidate) (toLower name))
+        null
+        known;
 
-    environment.etc."wsl.conf".text = generators.toINI { } (
-      lib.filterAttrsRecursive (_: v: v != "") config.wsl.wslConf
-    );
+      # WSL silently ignores keys it doesn't know, so a misspelled `systemd` or `enabled` quietly turns the feature off.
+      # Names close to a known one are errors, anything else may just be newer than this list.
+      problems = concatLists (mapAttrsToList
+        (section: values:
+          if knownKeys ? ${section} then
+            map
+              (key:
+                let typo = likelyTypo key knownKeys.${section}; in
+                {
+                  error = typo != null;
+                  message = "unknown key ${section}.${key}" + optionalString (typo != null) ", did you mean ${section}.${typo}?";
+                })
+              (filter (key: !(elem key knownKeys.${section})) (attrNames values))
+          else
+            let typo = likelyTypo section (attrNames knownKeys); in
+            [{
+              error = typo != null;
+              message = "unknown section ${section}" + optionalString (typo != null) ", did you mean ${typo}?";
+            }])
+        settings);
+    in
+    mkIf config.wsl.enable {
+
+      environment.etc."wsl.conf".text = generators.toINI { } settings;
 
-    warnings = optional (!config.wsl.wslConf.boot.systemd)
-      "systemd is disabled in wsl.conf. This is strongly discouraged and WILL break things"
-    ++ optional (config.wsl.wslConf.network.generateHosts && config.networking.extraHosts != "")
-      "networking.extraHosts has no effect if wsl.wslConf.network.generateHosts is true.";
+      assertions = map
+        (problem: {
+          assertion = false;
+          message = "wsl.wslConf: ${problem.message}";
+        })
+        (filter (problem: problem.error) problems);
 
-  };
+      warnings = map (problem: "wsl.wslConf: ${problem.message}") (filter (problem: !problem.error) problems)
+      ++ optional (!config.wsl.wslConf.boot.systemd)
+        "systemd is disabled in wsl.conf. This is strongly discouraged and WILL break things"
+      ++ optional (config.wsl.wslConf.network.generateHosts && config.networking.extraHosts != "")
+        "networking.extraHosts has no effect if wsl.wslConf.network.generateHosts is true.";
+
+    };
 
 }
@@ -4,6 +4,9 @@ with builtins; with lib;
 
 let
   cfg = config.wsl;
+
+  # The users activation script only exists when neither systemd-sysusers nor userborn manage users
+  classicUserActivation = !(config.systemd.sysusers.enable or false) && !(config.services.userborn.enable or false);
 in
 {
 
@@ -26,6 +29,11 @@ in
       default = "nixos";
       description = "The name of the default user";
     };
+    passwordlessSudoForWheel = mkOption {
+      type = bool;
+      default = true;
+      description = "Whether members of the wheel group can sudo without a password";
+    };
     populateBin = mkOption {
       type = bool;
       default = true;
@@ -61,6 +69,52 @@ in
       description = "Additional files to be added to /bin";
     };
     startMenuLaunchers = mkEnableOption "shortcuts for GUI applications in the windows start menu";
+    runtimeDir.enable = mkOption {
+      type = bool;
+      default = true;
+      description = ''
+        Whether to link the WSLg Wayland and PulseAudio sockets into each user's `XDG_RUNTIME_DIR` once systemd-logind has created it.
+        Shells that don't go through logind, e.g. `wsl.exe --exec`, `su` or root shells, get their runtime directory created and the variables set as well,
+        so GUI apps and the D-Bus session bus work there too.
+      '';
+    };
+    windowsDns = {
+      enable = mkEnableOption "a resolv.conf with the DNS servers Windows uses, including the ones of VPN connections, kept up to date while the distro runs";
+      interval = mkOption {
+        type = ints.positive;
+        default = 60;
+        description = ''
+          How often to ask Windows for its DNS servers, in seconds, on top of asking whenever the network of the distro changes.
+          Connecting a VPN on Windows doesn't change the network of the distro in NAT mode.
+        '';
+      };
+    };
+    clockSync = {
+      enable = mkEnableOption ''
+        correcting the clock when it drifted away from the Windows clock, e.g. after Windows slept or hibernated.
+        Chrony follows the Hyper-V clock already, but only steps the clock during its first few updates (`makestep 1.0 3`)
+      '';
+      source = mkOption {
+        type = enum [ "ptp" "windows" ];
+        default = "ptp";
+        description = "Where to get the correct time from: the Hyper-V PTP clock (`/dev/ptp0`), or Windows itself through `powershell.exe`";
+      };
+      interval = mkOption {
+        type = str;
+        default = "1min";
+        description = "How often to check the clock, as a systemd time span";
+      };
+      slewThreshold = mkOption {
+        type = float;
+        default = 0.05;
+        description = "Offsets below this many seconds are left alone";
+      };
+      stepThreshold = mkOption {
+        type = float;
+        default = 1.0;
+        description = "Offsets of at least this many seconds are corrected by setting the clock, smaller ones by slewing it";
+      };
+    };
   };
 
   config = mkIf cfg.enable {
@@ -107,48 +161,100 @@ in
     };
 
     environment = {
+      extraInit = mkIf cfg.runtimeDir.enable ''
+        if [ -z "''${XDG_RUNTIME_DIR:-}" ] || [ ! -d "$XDG_RUNTIME_DIR" ]; then
+          eval "$(${config.system.build.nativeUtils}/bin/nixos-wsl-runtime-dir --print-env 2>/dev/null)"
+        fi
+      '';
+
       # Only set the options if the files are managed by WSL
       etc = mkMerge [
         (mkIf config.wsl.wslConf.network.generateHosts {
           hosts.enable = false;
         })
-        (mkIf config.wsl.wslConf.network.generateResolvConf {
+        (mkIf (config.wsl.wslConf.network.generateResolvConf || cfg.windowsDns.enable) {
           "resolv.conf".enable = false;
         })
       ];
     };
 
-    # Make sure the WSLg X11 socket is available if /tmp is mounted to something else
-    systemd.mounts = [rec {
-      description = "Mount WSLg X11 socket";
-      what = "${cfg.wslConf.automount.root}/wslg/.X11-unix/X0";
-      where = "/tmp/.X11-unix/X0";
-      type = "none";
-      options = "bind";
-      after = [ "nixos-wsl-migration-x11mount.service" ];
-      wants = after;
-      wantedBy = [ "local-fs.target" ];
-    }];
-    # Remove symbolic link for WSLg X11 socket, which was created by NixOS-WSL until 2024-02-24
-    systemd.services.nixos-wsl-migration-x11mount = {
-      description = "Remove /tmp/.X11-unix symlink if present";
-      unitConfig = {
-        ConditionPathIsSymbolicLink = "/tmp/.X11-unix";
-        DefaultDependencies = "no";
-      };
+    # systemd mounts a fresh tmpfs on /tmp, which hides the WSLg X11 sockets.
+    # Also replaces the /tmp/.X11-unix symlink NixOS-WSL created until 2024-02-24.
+    systemd.services.wslg-x11 = {
+      description = "Mount the WSLg X11 sockets in /tmp";
+      after = [ "local-fs.target" "systemd-tmpfiles-setup.service" ];
+      before = [ "systemd-user-sessions.service" ];
+      wantedBy = [ "multi-user.target" ];
+      unitConfig.RequiresMountsFor = [ "/tmp" ];
       serviceConfig = {
         Type = "oneshot";
         RemainAfterExit = true;
-        ExecStart = "${pkgs.coreutils}/bin/rm /tmp/.X11-unix";
+        ExecStart = "${config.system.build.nativeUtils}/bin/nixos-wsl-wslg --wslg-dir=${cfg.wslConf.automount.root}/wslg";
       };
     };
 
+    # logind mounts a fresh tmpfs on the runtime directory, hiding the links WSL puts there
+    systemd.services."user-runtime-dir@".serviceConfig.ExecStartPost = mkIf cfg.runtimeDir.enable [
+      "${config.system.build.nativeUtils}/bin/nixos-wsl-runtime-dir --uid=%i"
+    ];
+
+    wsl.wslConf.network.generateResolvConf = mkIf cfg.windowsDns.enable (mkDefault false);
+    networking.resolvconf.enable = mkIf cfg.windowsDns.enable (mkDefault false);
+    systemd.services.wsl-resolv = mkIf cfg.windowsDns.enable {
+      description = "Generate resolv.conf from the DNS servers of Windows";
+      after = [ "network-pre.target" ];
+      before = [ "network.target" "nss-lookup.target" ];
+      wants = [ "nss-lookup.target" ];
+      wantedBy = [ "multi-user.target" ];
+      serviceConfig = {
+        # systemd services don't have the Windows PATH
+        ExecStart = "${config.system.build.nativeUtils}/bin/nixos-wsl-resolv --watch --interval=${toString cfg.windowsDns.interval} --powershell=${cfg.wslConf.automount.root}/c/Windows/System32/WindowsPowerShell/v1.0/powershell.exe";
+        Restart = "on-failure";
+        CacheDirectory = "nixos-wsl";
+      };
+    };
+
+    systemd.services.wsl-clock = mkIf cfg.clockSync.enable {
+      description = "Correct the clock after it drifted away from the Windows clock";
+      after = [ "chronyd.service" ];
+      serviceConfig = {
+        Type = "oneshot";
+        ExecStart = concatStringsSep " " [
+          "${config.system.build.nativeUtils}/bin/nixos-wsl-clock"
+          "--source=${cfg.clockSync.source}"
+          "--powershell=${cfg.wslConf.automount.root}/c/Windows/System32/WindowsPowerShell/v1.0/powershell.exe"
+          "--slew-threshold=${toString cfg.clockSync.slewThreshold}"
+          "--step-threshold=${toString cfg.clockSync.stepThreshold}"
+        ];
+      };
+    };
+    systemd.timers.wsl-clock = mkIf cfg.clockSync.enable {
+      wantedBy = [ "timers.target" ];
+      timerConfig = {
+        OnActiveSec = "0";
+        OnUnitActiveSec = cfg.clockSync.interval;
+        AccuracySec = "1s";
+      };
+    };
+
+    environment.systemPackages =
+      let
+        link = name: pkgs.runCommand name { } ''
+          mkdir -p $out/bin
+          ln -s ${config.system.build.nativeUtils}/bin/${name} $out/bin/
+        '';
+      in
+      [ (link "nixos-wsl-status") ]
+      ++ optional cfg.windowsDns.enable (link "nixos-wsl-resolv")
+      ++ optional cfg.clockSync.enable (link "nixos-wsl-clock");
+
     # dhcp is handled by windows
     networking.dhcpcd.enable = false;
 
     users.users.${cfg.defaultUser} = {
       isNormalUser = true;
-      uid = 1000;
+      # can be changed on installed systems, see migrateDefaultUid below
+      uid = mkDefault 1000;
       extraGroups = [ "wheel" ]; # Allow the default user to use sudo
     };
 
@@ -160,6 +266,26 @@ in
     security.sudo.wheelNeedsPassword = mkDefault (!cfg.passwordlessSudoForWheel);
 
     system.activationScripts = {
+      # With mutable users, NixOS refuses to apply UID changes to existing users, so do it ourselves before it runs.
+      # usermod also fixes the ownership of everything in the home directory.
+      migrateDefaultUid =
+        let
+          user = config.users.users.${cfg.defaultUser};
+        in
+        mkIf classicUserActivation (stringAfter [ "specialfs" ] ''
+          current_uid="$(getent passwd ${escapeShellArg user.name} | cut -d: -f3 || true)"
+          if [ -n "$current_uid" ] && [ "$current_uid" != ${toString user.uid} ]; then
+            echo "changing UID of ${user.name} from $current_uid to ${toString user.uid}..."
+            if usermod --uid ${toString user.uid} ${escapeShellArg user.name}; then
+              if [ -e /nix/var/nix/profiles/per-user/${escapeShellArg user.name} ]; then
+                chown -hR --from="$current_uid" ${toString user.uid} /nix/var/nix/profiles/per-user/${escapeShellArg user.name}
+              fi
+            else
+              echo "warning: could not change the UID of ${user.name}, it will be retried on the next boot" >&2
+            fi
+          fi
+        '');
+      users = mkIf classicUserActivation { deps = [ "migrateDefaultUid" ]; };
       copy-launchers = mkIf cfg.startMenuLaunchers (
         stringAfter [ ] ''
           for x in applications icons; do
@@ -237,14 +363,12 @@ in
       (optional ((length config.networking.nameservers) > 0 && config.wsl.wslConf.network.generateResolvConf)
         "custom nameservers are set (networking.nameservers), but resolv.conf is managed by WSL (wsl.wslConf.network.generateResolvConf)"
       )
-      (optional ((length config.networking.nameservers) == 0 && !config.services.resolved.enable && !config.wsl.wslConf.network.generateResolvConf)
+      (optional (cfg.windowsDns.enable && (config.wsl.wslConf.network.generateResolvConf || config.services.resolved.enable))
+        "resolv.conf is generated from the DNS servers of Windows (wsl.windowsDns.enable), but WSL (wsl.wslConf.network.generateResolvConf) or systemd-resolved manage it as well"
+      )
+      (optional ((length config.networking.nameservers) == 0 && !config.services.resolved.enable && !config.wsl.wslConf.network.generateResolvConf && !cfg.windowsDns.enable)
         "resolv.conf generation is turned off (wsl.wslConf.network.generateResolvConf), but no other nameservers are configured (networking.nameservers)"
       )
     ];
   };
 }
-    passwordlessSudoForWheel = mkOption {
-      type = bool;
-      default = true;
-      description = "Whether members of the wheel group can sudo without a password";
-    };
@@ -0,0 +1,18 @@
+use std::env;
+
+// Stamps the binaries with the NixOS-WSL version they were built for, which utils/default.nix passes in
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let mut stamp = Vec::new();
+    for var in ["NIXOS_WSL_RELEASE", "NIXOS_WSL_REV"] {
+        println!("cargo:rerun-if-env-changed={}", var);
+        let value = env::var(var).unwrap_or_else(|_| "unknown".to_string());
+        println!("cargo:rustc-env={}={}", var, value);
+        stamp.push(value);
+    }
+    println!(
+        "cargo:rustc-env=NIXOS_WSL_VERSION={} (NixOS-WSL {})",
+        env::var("CARGO_PKG_VERSION").unwrap(),
+        stamp.join(" ")
+    );
+}
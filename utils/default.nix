@@ -1,4 +1,10 @@
-{ rustPlatform, bash, coreutils }:
+{ rustPlatform
+, bash
+, coreutils
+  # Stamped into the binaries by build.rs, see wsl.version
+, release ? "unknown"
+, rev ? "unknown"
+}:
 rustPlatform.buildRustPackage {
   pname = "nixos-wsl-utils";
   version = "1.0.0";
@@ -9,5 +15,7 @@ rustPlatform.buildRustPackage {
   env = {
     NIXOS_WSL_SH = "${bash}/bin/sh";
     NIXOS_WSL_ENV = "${coreutils}/bin/env";
+    NIXOS_WSL_RELEASE = release;
+    NIXOS_WSL_REV = rev;
   };
 }
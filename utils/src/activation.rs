@@ -0,0 +1,211 @@
+use anyhow::Context;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ExitStatus};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+/// How many lines of activation output are kept around to be logged again on a timeout
+const TAIL_LINES: usize = 20;
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where the output of an activation ends up, besides the log
+#[derive(Debug, Default)]
+struct Sink {
+    /// The most recent lines
+    tail: VecDeque<String>,
+    file: Option<File>,
+}
+
+impl Sink {
+    fn push(&mut self, line: String) {
+        if let Some(file) = &mut self.file {
+            if let Err(e) = writeln!(file, "{}", line) {
+                log::warn!("Could not write activation log, closing it: {}", e);
+                self.file = None;
+            }
+        }
+        if self.tail.len() == TAIL_LINES {
+            self.tail.pop_front();
+        }
+        self.tail.push_back(line);
+    }
+}
+
+/// Forwards the output of the activation script to the log line by line, keeping the last few lines
+pub struct Output {
+    sink: Arc<Mutex<Sink>>,
+    done: Receiver<()>,
+    readers: usize,
+}
+
+fn spawn_reader(pipe: impl Read + Send + 'static, sink: Arc<Mutex<Sink>>, done: Sender<()>) {
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).split(b'\n') {
+            let Ok(line) = line else { break };
+            let line = String::from_utf8_lossy(&line).into_owned();
+            log::info!(target: "activation", "{}", line);
+            if let Ok(mut sink) = sink.lock() {
+                sink.push(line);
+            }
+        }
+        let _ = done.send(());
+    });
+}
+
+impl Output {
+    /// Takes the child's stdout and stderr, which have to be piped.
+    /// The output is also copied to `file`, if there is one.
+    pub fn capture(child: &mut Child, file: Option<File>) -> Output {
+        let sink = Arc::new(Mutex::new(Sink {
+            file,
+            ..Sink::default()
+        }));
+        let (sender, done) = channel();
+        let mut readers = 0;
+        if let Some(stdout) = child.stdout.take() {
+            spawn_reader(stdout, sink.clone(), sender.clone());
+            readers += 1;
+        }
+        if let Some(stderr) = child.stderr.take() {
+            spawn_reader(stderr, sink.clone(), sender);
+            readers += 1;
+        }
+        Output {
+            sink,
+            done,
+            readers,
+        }
+    }
+
+    /// Gives the readers some time to forward what's still in the pipes.
+    /// Processes started by the script can keep the pipes open, so this doesn't wait for EOF forever.
+    pub fn finish(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        for _ in 0..self.readers {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if self.done.recv_timeout(left).is_err() {
+                break;
+            }
+        }
+    }
+
+    pub fn tail(&self) -> Vec<String> {
+        self.sink
+            .lock()
+            .map(|sink| sink.tail.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Wait {
+    Exited(ExitStatus),
+    TimedOut,
+}
+
+/// Waits for the child to exit, or until `timeout` has passed if there is one
+pub fn wait_timeout(child: &mut Child, timeout: Option<Duration>) -> std::io::Result<Wait> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Wait::Exited(status));
+        }
+        if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+            return Ok(Wait::TimedOut);
+        }
+        sleep(POLL_INTERVAL);
+    }
+}
+
+/// Asks the child to exit with SIGTERM and kills it if it is still around after `grace`
+pub fn terminate(child: &mut Child, grace: Duration) -> anyhow::Result<()> {
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).context("When sending SIGTERM")?;
+    if wait_timeout(child, Some(grace)).context("When waiting")? == Wait::TimedOut {
+        log::warn!("Still running after {:?}, sending SIGKILL...", grace);
+        child.kill().context("When sending SIGKILL")?;
+        child.wait().context("When waiting")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::open_log;
+    use crate::test_util::scratch_dir;
+    use std::process::{Command, Stdio};
+
+    fn sh(script: &str) -> Child {
+        Command::new("/bin/sh")
+            .args(["-c", script])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    #[test]
+    fn tail_keeps_last_lines() {
+        let mut sink = Sink::default();
+        for i in 0..TAIL_LINES + 5 {
+            sink.push(i.to_string());
+        }
+        assert_eq!(sink.tail.len(), TAIL_LINES);
+        assert_eq!(sink.tail.front().map(String::as_str), Some("5"));
+    }
+
+    #[test]
+    fn captures_stdout_and_stderr() {
+        let dir = scratch_dir("activation-output");
+        let file = open_log(&dir, "activation", 3, 1700000000).unwrap();
+        let mut child = sh("echo out; echo err >&2");
+        let output = Output::capture(&mut child, Some(file));
+        assert!(matches!(
+            wait_timeout(&mut child, None).unwrap(),
+            Wait::Exited(status) if status.success()
+        ));
+        output.finish(Duration::from_secs(5));
+        let mut tail = output.tail();
+        tail.sort();
+        assert_eq!(tail, vec!["err", "out"]);
+
+        let mut logged: Vec<String> =
+            std::fs::read_to_string(dir.join("activation-1700000000.log"))
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect();
+        logged.sort();
+        assert_eq!(logged, tail);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn hung_child_times_out_and_is_terminated() {
+        let mut child = sh("sleep 10");
+        assert_eq!(
+            wait_timeout(&mut child, Some(Duration::from_millis(50))).unwrap(),
+            Wait::TimedOut
+        );
+        terminate(&mut child, Duration::from_secs(5)).unwrap();
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn child_ignoring_sigterm_is_killed() {
+        let mut child = sh("trap '' TERM; echo ready; sleep 10");
+        let output = Output::capture(&mut child, None);
+        // Make sure the trap is set up before signalling
+        while output.tail().is_empty() {
+            sleep(POLL_INTERVAL);
+        }
+        terminate(&mut child, Duration::from_millis(100)).unwrap();
+        assert!(child.try_wait().unwrap().is_some());
+    }
+}
@@ -0,0 +1,215 @@
+use anyhow::{bail, Context};
+use clap::Parser;
+use nix::mount::{mount, MsFlags};
+use std::fs::{read_to_string, write};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+
+/// Registers the binfmt_misc handler that lets WSL run Windows executables
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-binfmt", version = build_info::VERSION)]
+struct Args {
+    #[arg(long, default_value = "/proc/sys/fs/binfmt_misc")]
+    binfmt_misc: PathBuf,
+
+    #[arg(long, default_value = "WSLInterop")]
+    name: String,
+
+    /// WSL's init, which runs the executable on the Windows side
+    #[arg(long, default_value = "/init")]
+    interpreter: String,
+
+    /// Only check the registration, don't change anything
+    #[arg(long)]
+    check: bool,
+}
+
+/// `MZ`, the magic at the start of every PE executable
+const MAGIC: &str = "MZ";
+/// P keeps argv[0], F opens the interpreter right away, so it works in other mount namespaces too
+const FLAGS: &str = "PF";
+
+fn registration(name: &str, interpreter: &str) -> String {
+    format!(":{}:M::{}::{}:{}", name, MAGIC, interpreter, FLAGS)
+}
+
+/// The parts of /proc/sys/fs/binfmt_misc/<name> that matter to us
+#[derive(Debug, Default, PartialEq)]
+struct Status {
+    enabled: bool,
+    interpreter: Option<String>,
+    flags: String,
+    magic: Option<String>,
+}
+
+fn parse_status(status: &str) -> Status {
+    let mut result = Status::default();
+    for line in status.lines() {
+        if line == "enabled" {
+            result.enabled = true;
+        } else if let Some(interpreter) = line.strip_prefix("interpreter ") {
+            result.interpreter = Some(interpreter.to_string());
+        } else if let Some(flags) = line.strip_prefix("flags: ") {
+            result.flags = flags.to_string();
+        } else if let Some(magic) = line.strip_prefix("magic ") {
+            result.magic = Some(magic.to_string());
+        }
+    }
+    result
+}
+
+/// Describes what is wrong with an existing registration, if anything
+fn problems(status: &Status, interpreter: &str) -> Vec<String> {
+    let mut problems = vec![];
+    if !status.enabled {
+        problems.push("disabled".to_string());
+    }
+    if status.interpreter.as_deref() != Some(interpreter) {
+        problems.push(format!(
+            "interpreter is {}, expected {}",
+            status.interpreter.as_deref().unwrap_or("missing"),
+            interpreter
+        ));
+    }
+    let hex_magic: String = MAGIC.bytes().map(|b| format!("{:02x}", b)).collect();
+    if status.magic.as_deref() != Some(hex_magic.as_str()) {
+        problems.push(format!(
+            "magic is {}, expected {}",
+            status.magic.as_deref().unwrap_or("missing"),
+            hex_magic
+        ));
+    }
+    for flag in FLAGS.chars() {
+        if !status.flags.contains(flag) {
+            problems.push(format!("flag {} is missing", flag));
+        }
+    }
+    problems
+}
+
+fn read_status(entry: &Path) -> anyhow::Result<Option<Status>> {
+    match read_to_string(entry) {
+        Ok(status) => Ok(Some(parse_status(&status))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("When reading {}", entry.display())),
+    }
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    let register = args.binfmt_misc.join("register");
+    let entry = args.binfmt_misc.join(&args.name);
+
+    if !register.exists() {
+        if args.check {
+            bail!(
+                "binfmt_misc is not mounted at {}",
+                args.binfmt_misc.display()
+            );
+        }
+        mount(
+            Some("binfmt_misc"),
+            &args.binfmt_misc,
+            Some("binfmt_misc"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            None::<&str>,
+        )
+        .with_context(|| {
+            format!(
+                "When mounting binfmt_misc on {}",
+                args.binfmt_misc.display()
+            )
+        })?;
+    }
+
+    match read_status(&entry)? {
+        Some(status) => {
+            let problems = problems(&status, &args.interpreter);
+            if problems.is_empty() {
+                println!("{} is registered", args.name);
+                return Ok(());
+            }
+            if args.check {
+                bail!("{} is registered wrong: {}", args.name, problems.join(", "));
+            }
+            eprintln!(
+                "{} is registered wrong ({}), registering it again",
+                args.name,
+                problems.join(", ")
+            );
+            // Writing -1 to an entry removes it
+            write(&entry, "-1").with_context(|| format!("When removing {}", entry.display()))?;
+        }
+        None if args.check => bail!("{} is not registered", args.name),
+        None => {}
+    }
+
+    write(&register, registration(&args.name, &args.interpreter))
+        .with_context(|| format!("When writing {}", register.display()))?;
+
+    let status =
+        read_status(&entry)?.with_context(|| format!("{} did not show up", entry.display()))?;
+    let problems = problems(&status, &args.interpreter);
+    if !problems.is_empty() {
+        bail!("{} is registered wrong: {}", args.name, problems.join(", "));
+    }
+    println!("Registered {}", args.name);
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WSL_STATUS: &str = "enabled\ninterpreter /init\nflags: PF\noffset 0\nmagic 4d5a\n";
+
+    #[test]
+    fn builds_registration() {
+        assert_eq!(
+            registration("WSLInterop", "/init"),
+            ":WSLInterop:M::MZ::/init:PF"
+        );
+    }
+
+    #[test]
+    fn parses_status() {
+        assert_eq!(
+            parse_status(WSL_STATUS),
+            Status {
+                enabled: true,
+                interpreter: Some("/init".to_string()),
+                flags: "PF".to_string(),
+                magic: Some("4d5a".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn accepts_wsl_registration() {
+        assert!(problems(&parse_status(WSL_STATUS), "/init").is_empty());
+    }
+
+    #[test]
+    fn finds_problems() {
+        let status = parse_status("disabled\ninterpreter /bin/wine\nflags: \nmagic 4d5a\n");
+        assert_eq!(
+            problems(&status, "/init"),
+            vec![
+                "disabled",
+                "interpreter is /bin/wine, expected /init",
+                "flag P is missing",
+                "flag F is missing"
+            ]
+        );
+    }
+}
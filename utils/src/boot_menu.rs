@@ -0,0 +1,288 @@
+#[cfg(test)]
+mod test_util;
+
+use anyhow::{anyhow, bail, Context};
+use clap::Parser;
+use std::fs::{
+    canonicalize, create_dir_all, read_dir, read_to_string, remove_file, symlink_metadata,
+};
+use std::io::{self, BufRead, ErrorKind, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+use nixos_wsl_utils::generations::{generation_number, NEXT_BOOT_POINTER};
+
+/// Pick a NixOS generation to boot
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-boot-menu", version = build_info::VERSION)]
+struct Args {
+    #[arg(long, default_value = "/nix/var/nix/profiles")]
+    profiles_dir: PathBuf,
+
+    /// Seconds to wait for a choice before booting the default, 0 waits forever
+    #[arg(long, default_value = "0")]
+    timeout: u64,
+
+    /// Print the chosen system to stdout instead of staging it for the next boot.
+    /// Prints nothing if the default was chosen.
+    #[arg(long)]
+    print: bool,
+}
+
+#[derive(Debug, PartialEq)]
+struct Entry {
+    number: u64,
+    path: PathBuf,
+    /// Seconds since the epoch
+    created: Option<u64>,
+    version: String,
+    current: bool,
+}
+
+/// Generations in the profile, newest first
+fn list_generations(profiles_dir: &Path) -> anyhow::Result<Vec<Entry>> {
+    let current = canonicalize(profiles_dir.join("system")).ok();
+    let mut entries = vec![];
+    for entry in read_dir(profiles_dir)
+        .with_context(|| format!("When listing {}", profiles_dir.display()))?
+    {
+        let entry = entry.context("When listing generations")?;
+        let Some(number) = entry.file_name().to_str().and_then(generation_number) else {
+            continue;
+        };
+        let path = entry.path();
+        entries.push(Entry {
+            number,
+            created: symlink_metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs()),
+            version: read_to_string(path.join("nixos-version"))
+                .map(|v| v.trim().to_string())
+                .unwrap_or_else(|_| "unknown version".to_string()),
+            current: current.is_some() && canonicalize(&path).ok() == current,
+            path,
+        });
+    }
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.number));
+    Ok(entries)
+}
+
+/// Formats a unix timestamp as `YYYY-MM-DD HH:MM` (UTC)
+fn format_time(secs: u64) -> String {
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60
+    )
+}
+
+fn render_menu(entries: &[Entry]) -> String {
+    let mut menu = String::from("NixOS-WSL boot menu\n\n");
+    for entry in entries {
+        menu.push_str(&format!(
+            "{:>5}  {}  {}{}\n",
+            entry.number,
+            entry
+                .created
+                .map(format_time)
+                .unwrap_or_else(|| "????-??-?? ??:??".to_string()),
+            entry.version,
+            if entry.current { " (current)" } else { "" }
+        ));
+    }
+    menu
+}
+
+/// Turns the user's answer into a generation. An empty answer picks the default.
+fn choose<'a>(entries: &'a [Entry], answer: &str) -> anyhow::Result<Option<&'a Entry>> {
+    let answer = answer.trim();
+    if answer.is_empty() {
+        return Ok(None);
+    }
+    let number: u64 = answer
+        .parse()
+        .map_err(|_| anyhow!("not a generation number: {}", answer))?;
+    entries
+        .iter()
+        .find(|entry| entry.number == number)
+        .map(Some)
+        .ok_or(anyhow!("no such generation: {}", number))
+}
+
+/// Reads a line from stdin, or returns None once `timeout` has passed
+fn read_answer(timeout: Option<Duration>) -> anyhow::Result<Option<String>> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        let _ = sender.send(io::stdin().lock().read_line(&mut line).map(|_| line));
+    });
+    let result = match timeout {
+        Some(timeout) => match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(_) => return Ok(None),
+        },
+        None => receiver.recv().context("When reading stdin")?,
+    };
+    Ok(Some(result.context("When reading stdin")?))
+}
+
+fn stage(path: &Path) -> anyhow::Result<()> {
+    let pointer = Path::new(NEXT_BOOT_POINTER);
+    if let Some(parent) = pointer.parent() {
+        create_dir_all(parent).with_context(|| format!("When creating {}", parent.display()))?;
+    }
+    match remove_file(pointer) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).with_context(|| format!("When removing {}", pointer.display()))
+        }
+        _ => {}
+    }
+    // Point at the store path, so the profile link can be garbage collected without breaking the pointer
+    let target =
+        canonicalize(path).with_context(|| format!("When resolving {}", path.display()))?;
+    symlink(&target, pointer).with_context(|| format!("When creating {}", pointer.display()))
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    let entries = list_generations(&args.profiles_dir)?;
+    if entries.is_empty() {
+        bail!("No generations in {}", args.profiles_dir.display());
+    }
+
+    // The menu goes to stderr, so stdout only carries the answer for the shim
+    let mut stderr = io::stderr();
+    write!(stderr, "{}", render_menu(&entries))?;
+    let timeout = Some(Duration::from_secs(args.timeout)).filter(|t| !t.is_zero());
+
+    let chosen = loop {
+        match timeout {
+            Some(timeout) => write!(
+                stderr,
+                "\nGeneration to boot (Enter for the default, booting it in {}s): ",
+                timeout.as_secs()
+            )?,
+            None => write!(stderr, "\nGeneration to boot (Enter for the default): ")?,
+        }
+        stderr.flush()?;
+
+        let Some(answer) = read_answer(timeout)? else {
+            writeln!(stderr)?;
+            break None;
+        };
+        match choose(&entries, &answer) {
+            Ok(chosen) => break chosen,
+            Err(e) => writeln!(stderr, "{}", e)?,
+        }
+    };
+
+    match (chosen, args.print) {
+        (Some(entry), true) => println!("{}", entry.path.display()),
+        (Some(entry), false) => {
+            stage(&entry.path)?;
+            writeln!(
+                stderr,
+                "Generation {} will be booted the next time the distro is started",
+                entry.number
+            )?;
+        }
+        (None, _) => {}
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::fs::{remove_dir_all, write};
+
+    fn entry(number: u64, current: bool) -> Entry {
+        Entry {
+            number,
+            path: PathBuf::from(format!("/nix/var/nix/profiles/system-{}-link", number)),
+            created: Some(1700000000),
+            version: "24.05.20240101.abcdef".to_string(),
+            current,
+        }
+    }
+
+    #[test]
+    fn formats_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(format_time(1700000000), "2023-11-14 22:13");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00");
+    }
+
+    #[test]
+    fn renders_menu() {
+        assert_eq!(
+            render_menu(&[entry(42, true), entry(7, false)]),
+            "NixOS-WSL boot menu\n\n   42  2023-11-14 22:13  24.05.20240101.abcdef (current)\n    7  2023-11-14 22:13  24.05.20240101.abcdef\n"
+        );
+    }
+
+    #[test]
+    fn chooses_generations() {
+        let entries = [entry(42, true), entry(7, false)];
+        assert_eq!(choose(&entries, "\n").unwrap(), None);
+        assert_eq!(choose(&entries, " 7\n").unwrap(), Some(&entries[1]));
+        assert!(choose(&entries, "8").is_err());
+        assert!(choose(&entries, "latest").is_err());
+    }
+
+    #[test]
+    fn lists_generations_newest_first() {
+        let dir = scratch_dir("boot-menu");
+        for number in [1, 2, 10] {
+            let system = dir.join(number.to_string());
+            create_dir_all(&system).unwrap();
+            write(system.join("nixos-version"), format!("24.05.{}\n", number)).unwrap();
+            symlink(&system, dir.join(format!("system-{}-link", number))).unwrap();
+        }
+        symlink("system-2-link", dir.join("system")).unwrap();
+
+        let entries = list_generations(&dir).unwrap();
+        let summary: Vec<(u64, &str, bool)> = entries
+            .iter()
+            .map(|e| (e.number, e.version.as_str(), e.current))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (10, "24.05.10", false),
+                (2, "24.05.2", true),
+                (1, "24.05.1", false)
+            ]
+        );
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use crate::interop::run_with_timeout;
+use crate::quote::json_string;
+
+/// Set by build.rs
+pub const VERSION: &str = env!("NIXOS_WSL_VERSION");
+
+/// wslinfo talks to the WSL service, which shouldn't be able to hang a bug report
+const WSLINFO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a tool was built from and what it runs on, for bug reports
+#[derive(Debug, PartialEq)]
+pub struct BuildInfo {
+    pub program: &'static str,
+    pub version: &'static str,
+    pub release: &'static str,
+    pub rev: &'static str,
+    /// Of the generation that is running now
+    pub nixos: Option<String>,
+    pub kernel: Option<String>,
+    pub wsl: Option<String>,
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    read_to_string(path)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+}
+
+/// Asks `wslinfo`, which WSL 2.0 and newer put into every distro
+fn wsl_version() -> Option<String> {
+    ["wslinfo", "/usr/bin/wslinfo"].iter().find_map(|wslinfo| {
+        let output =
+            run_with_timeout(Command::new(wslinfo).arg("--wsl-version"), WSLINFO_TIMEOUT).ok()?;
+        let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (output.status.success() && !version.is_empty()).then_some(version)
+    })
+}
+
+impl BuildInfo {
+    pub fn detect(program: &'static str) -> BuildInfo {
+        BuildInfo {
+            program,
+            version: env!("CARGO_PKG_VERSION"),
+            release: env!("NIXOS_WSL_RELEASE"),
+            rev: env!("NIXOS_WSL_REV"),
+            nixos: read_trimmed(Path::new("/run/current-system/nixos-version")),
+            kernel: read_trimmed(Path::new("/proc/sys/kernel/osrelease")),
+            wsl: wsl_version(),
+        }
+    }
+
+    pub fn format_text(&self) -> String {
+        let known = |value: &Option<String>| value.clone().unwrap_or_else(|| "unknown".to_string());
+        let mut result = String::new();
+        let _ = writeln!(result, "{}: {}", self.program, self.version);
+        let _ = writeln!(result, "NixOS-WSL: {} ({})", self.release, self.rev);
+        let _ = writeln!(result, "NixOS: {}", known(&self.nixos));
+        let _ = writeln!(result, "kernel: {}", known(&self.kernel));
+        let _ = writeln!(result, "WSL: {}", known(&self.wsl));
+        result
+    }
+
+    pub fn format_json(&self) -> String {
+        let optional =
+            |value: &Option<String>| value.as_deref().map_or("null".to_string(), json_string);
+        format!(
+            "{{\"program\":{},\"version\":{},\"release\":{},\"rev\":{},\"nixos\":{},\"kernel\":{},\"wsl\":{}}}",
+            json_string(self.program),
+            json_string(self.version),
+            json_string(self.release),
+            json_string(self.rev),
+            optional(&self.nixos),
+            optional(&self.kernel),
+            optional(&self.wsl)
+        )
+    }
+}
+
+fn wants_json_version(args: &[OsString]) -> bool {
+    args.len() == 2 && args.contains(&"--version".into()) && args.contains(&"--json".into())
+}
+
+/// clap's `--version` can only print the version string, so `program --version --json`
+/// prints the whole build info and exits before the arguments get to clap
+pub fn handle_json_version(program: &'static str) {
+    let args: Vec<OsString> = env::args_os().skip(1).collect();
+    if wants_json_version(&args) {
+        println!("{}", BuildInfo::detect(program).format_json());
+        std::process::exit(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(wsl: Option<&str>) -> BuildInfo {
+        BuildInfo {
+            program: "systemd-shim",
+            version: "0.1.0",
+            release: "2411.6.0",
+            rev: "abc123",
+            nixos: Some("24.11.20250101.deadbee (Vicuna)".to_string()),
+            kernel: Some("6.6.36.6-microsoft-standard-WSL2".to_string()),
+            wsl: wsl.map(String::from),
+        }
+    }
+
+    #[test]
+    fn formats_text() {
+        assert_eq!(
+            info(None).format_text(),
+            "systemd-shim: 0.1.0\nNixOS-WSL: 2411.6.0 (abc123)\nNixOS: 24.11.20250101.deadbee (Vicuna)\nkernel: 6.6.36.6-microsoft-standard-WSL2\nWSL: unknown\n"
+        );
+    }
+
+    #[test]
+    fn formats_json() {
+        assert_eq!(
+            info(Some("2.3.26.0")).format_json(),
+            r#"{"program":"systemd-shim","version":"0.1.0","release":"2411.6.0","rev":"abc123","nixos":"24.11.20250101.deadbee (Vicuna)","kernel":"6.6.36.6-microsoft-standard-WSL2","wsl":"2.3.26.0"}"#
+        );
+        assert!(info(None).format_json().ends_with(r#""wsl":null}"#));
+    }
+
+    #[test]
+    fn recognizes_json_version() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert!(wants_json_version(&args(&["--version", "--json"])));
+        assert!(wants_json_version(&args(&["--json", "--version"])));
+        assert!(!wants_json_version(&args(&["--version"])));
+        assert!(!wants_json_version(&args(&["--json"])));
+        assert!(!wants_json_version(&args(&[
+            "--version",
+            "--json",
+            "out.tar"
+        ])));
+    }
+
+    #[test]
+    fn version_is_stamped() {
+        assert!(VERSION.starts_with(env!("CARGO_PKG_VERSION")));
+        assert!(VERSION.contains("NixOS-WSL"));
+    }
+}
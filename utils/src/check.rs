@@ -0,0 +1,288 @@
+use anyhow::bail;
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use crate::config::{ShimConfig, CONFIG_PATH};
+use crate::hugepages::nr_hugepages_path;
+use crate::pipeline::Pipeline;
+use nixos_wsl_utils::generations::Generation;
+use nixos_wsl_utils::quote::json_string;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Ok,
+    /// Booting works, but something will be missing
+    Warning,
+    /// Booting fails, or the boot can't be debugged
+    Error,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Ok => "ok",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub check: &'static str,
+    pub level: Level,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn new(check: &'static str, level: Level, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            check,
+            level,
+            message: message.into(),
+        }
+    }
+
+    fn format_text(&self) -> String {
+        format!("{:7} {}: {}", self.level.name(), self.check, self.message)
+    }
+
+    fn format_json(&self) -> String {
+        format!(
+            "{{\"check\":{},\"level\":{},\"message\":{}}}",
+            json_string(self.check),
+            json_string(self.level.name()),
+            json_string(&self.message)
+        )
+    }
+}
+
+fn check_path(check: &'static str, path: &Path, what: &str, missing: Level) -> Diagnostic {
+    if path.exists() {
+        Diagnostic::new(check, Level::Ok, format!("{} {}", what, path.display()))
+    } else {
+        Diagnostic::new(
+            check,
+            missing,
+            format!("{} {} does not exist", what, path.display()),
+        )
+    }
+}
+
+fn check_dev_shm(dev_shm: &Path, run_shm: &Path) -> Diagnostic {
+    if dev_shm.exists() || run_shm.exists() {
+        Diagnostic::new(
+            "dev-shm",
+            Level::Ok,
+            format!("{} or {} exists", dev_shm.display(), run_shm.display()),
+        )
+    } else {
+        Diagnostic::new(
+            "dev-shm",
+            Level::Warning,
+            format!(
+                "neither {} nor {} exists, the dev-shm step will mount a new tmpfs on {}",
+                dev_shm.display(),
+                run_shm.display(),
+                dev_shm.display()
+            ),
+        )
+    }
+}
+
+fn check_kmsg(path: &Path) -> Diagnostic {
+    match OpenOptions::new().write(true).open(path) {
+        Ok(_) => Diagnostic::new("kmsg", Level::Ok, format!("{} is writable", path.display())),
+        Err(e) => Diagnostic::new(
+            "kmsg",
+            Level::Error,
+            format!(
+                "{} is not writable, boot logs will be lost: {}",
+                path.display(),
+                e
+            ),
+        ),
+    }
+}
+
+/// Checks what the boot of `generation` depends on.
+/// `config_error` is why the config couldn't be loaded, in which case `config` is the default one.
+pub fn diagnose(
+    config: &ShimConfig,
+    config_error: Option<&anyhow::Error>,
+    generation: &Generation,
+    pipeline: &Pipeline,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![check_kmsg(Path::new("/dev/kmsg"))];
+
+    diagnostics.push(match config_error {
+        None => Diagnostic::new("config", Level::Ok, format!("{} is valid", CONFIG_PATH)),
+        Some(e) => Diagnostic::new(
+            "config",
+            Level::Error,
+            format!("{:#}, booting would use the defaults", e),
+        ),
+    });
+    let mut unknown: Vec<&String> = config
+        .disabled_steps
+        .iter()
+        .filter(|s| !pipeline.has_step(s))
+        .collect();
+    unknown.sort();
+    for step in unknown {
+        diagnostics.push(Diagnostic::new(
+            "config",
+            Level::Warning,
+            format!("unknown boot step {} is disabled", step),
+        ));
+    }
+    let mut unknown: Vec<&String> = config
+        .step_policies
+        .keys()
+        .filter(|s| !pipeline.has_step(s))
+        .collect();
+    unknown.sort();
+    for step in unknown {
+        diagnostics.push(Diagnostic::new(
+            "config",
+            Level::Warning,
+            format!("unknown boot step {} has a failure policy", step),
+        ));
+    }
+
+    diagnostics.push(check_path(
+        "activation",
+        &generation.activate(),
+        "activation script",
+        Level::Error,
+    ));
+    let systemd = config
+        .systemd
+        .path
+        .clone()
+        .unwrap_or_else(|| generation.systemd());
+    diagnostics.push(check_path("systemd", &systemd, "systemd", Level::Error));
+    diagnostics.push(check_path(
+        "store",
+        Path::new("/nix/store"),
+        "store",
+        Level::Error,
+    ));
+
+    diagnostics.push(check_dev_shm(Path::new("/dev/shm"), Path::new("/run/shm")));
+
+    if config.hugepages.pages > 0 {
+        diagnostics.push(match config.hugepages.page_size_kb {
+            Some(size) => check_path(
+                "hugepages",
+                &nr_hugepages_path(size),
+                "hugepage pool",
+                Level::Warning,
+            ),
+            None => check_path(
+                "hugepages",
+                Path::new("/sys/kernel/mm/hugepages"),
+                "hugepage support",
+                Level::Warning,
+            ),
+        });
+    }
+
+    if let Some(path) = &config.boot_menu.path {
+        diagnostics.push(check_path("boot-menu", path, "boot menu", Level::Warning));
+    }
+    if let Some(path) = &config.first_boot.path {
+        diagnostics.push(check_path(
+            "first-boot",
+            path,
+            "first boot provisioning",
+            Level::Warning,
+        ));
+    }
+
+    diagnostics
+}
+
+/// Prints the diagnostics, one per line, and fails if any of them is an error
+pub fn report(diagnostics: &[Diagnostic], json: bool) -> anyhow::Result<()> {
+    for diagnostic in diagnostics {
+        if json {
+            println!("{}", diagnostic.format_json());
+        } else {
+            println!("{}", diagnostic.format_text());
+        }
+    }
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.level == Level::Error)
+        .count();
+    if errors > 0 {
+        bail!("{} of {} checks failed", errors, diagnostics.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boot_steps;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn formats_diagnostics() {
+        let diagnostic = Diagnostic::new("systemd", Level::Error, "\"x\" does not exist");
+        assert_eq!(
+            diagnostic.format_text(),
+            "error   systemd: \"x\" does not exist"
+        );
+        assert_eq!(
+            diagnostic.format_json(),
+            r#"{"check":"systemd","level":"error","message":"\"x\" does not exist"}"#
+        );
+    }
+
+    #[test]
+    fn reports_missing_paths() {
+        let missing =
+            std::env::temp_dir().join(format!("nixos-wsl-missing-{}", std::process::id()));
+        assert_eq!(
+            check_path("store", &std::env::temp_dir(), "store", Level::Error).level,
+            Level::Ok
+        );
+        assert_eq!(
+            check_path("store", &missing, "store", Level::Warning).level,
+            Level::Warning
+        );
+    }
+
+    #[test]
+    fn reports_missing_dev_shm() {
+        let dir = scratch_dir("check-dev-shm");
+        let diagnostic = check_dev_shm(&dir.join("dev-shm"), &dir.join("run-shm"));
+        assert_eq!(diagnostic.level, Level::Warning);
+        assert!(diagnostic.message.contains("will mount a new tmpfs"));
+        assert_eq!(check_dev_shm(&dir.join("dev-shm"), &dir).level, Level::Ok);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn diagnoses_broken_generation() {
+        let mut config = ShimConfig::default();
+        config.disabled_steps.insert("no-such-step".to_string());
+        let generation = Generation::new("/nonexistent/system");
+        let pipeline = Pipeline::new(boot_steps(&config, &generation)).unwrap();
+        let diagnostics = diagnose(&config, None, &generation, &pipeline);
+
+        let level = |check: &str| {
+            diagnostics
+                .iter()
+                .filter(|d| d.check == check)
+                .map(|d| d.level)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(level("activation"), vec![Level::Error]);
+        assert_eq!(level("systemd"), vec![Level::Error]);
+        assert_eq!(level("config"), vec![Level::Ok, Level::Warning]);
+        assert!(report(&diagnostics, false).is_err());
+    }
+}
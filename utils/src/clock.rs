@@ -0,0 +1,285 @@
+use anyhow::{bail, Context};
+use clap::{Parser, ValueEnum};
+use nix::errno::Errno;
+use nix::libc::{adjtimex, clockid_t, timex, ADJ_OFFSET_SINGLESHOT};
+use nix::sys::time::TimeSpec;
+use nix::time::{clock_gettime, clock_settime, ClockId};
+use std::env;
+use std::fs::File;
+use std::mem::MaybeUninit;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+use nixos_wsl_utils::interop::{run_with_timeout, use_live_socket};
+
+/// Corrects the clock of the distro after it drifted away from the Windows clock, e.g. after Windows slept or hibernated.
+/// Small offsets are slewed away, so time never jumps backwards for running programs, large ones are stepped.
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-clock", version = build_info::VERSION)]
+struct Args {
+    /// Where to get the correct time from
+    #[arg(long, value_enum, default_value = "ptp")]
+    source: Source,
+
+    /// The Hyper-V clock that follows the Windows clock, for --source=ptp
+    #[arg(long, default_value = "/dev/ptp0")]
+    ptp_device: PathBuf,
+
+    /// For --source=windows
+    #[arg(long, default_value = "powershell.exe")]
+    powershell: PathBuf,
+
+    /// How long to wait for PowerShell, in seconds
+    #[arg(long, default_value = "15")]
+    timeout: u64,
+
+    /// Offsets below this many seconds are left alone
+    #[arg(long, default_value = "0.05")]
+    slew_threshold: f64,
+
+    /// Offsets of at least this many seconds are corrected by setting the clock instead of slewing it
+    #[arg(long, default_value = "1")]
+    step_threshold: f64,
+
+    /// Only print the offset, don't change the clock
+    #[arg(long)]
+    check: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Source {
+    /// The Hyper-V PTP clock, cheap enough to read every few seconds
+    Ptp,
+    /// Ask Windows through interop, for when there is no PTP clock
+    Windows,
+}
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// How far the reference clock is ahead of ours
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Offset {
+    nanos: i64,
+    /// How far off the measurement itself may be, from the time it took
+    uncertainty: i64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Correction {
+    None,
+    Slew(i64),
+    Step(i64),
+}
+
+fn to_nanos(time: TimeSpec) -> i64 {
+    time.tv_sec() * NANOS_PER_SEC + time.tv_nsec()
+}
+
+fn from_nanos(nanos: i64) -> TimeSpec {
+    TimeSpec::new(
+        nanos.div_euclid(NANOS_PER_SEC) as _,
+        nanos.rem_euclid(NANOS_PER_SEC) as _,
+    )
+}
+
+fn seconds(nanos: i64) -> f64 {
+    nanos as f64 / NANOS_PER_SEC as f64
+}
+
+fn realtime() -> anyhow::Result<i64> {
+    Ok(to_nanos(
+        clock_gettime(ClockId::CLOCK_REALTIME).context("When reading the clock")?,
+    ))
+}
+
+/// Compares `reference` with our clock, halfway between the reads before and after it
+fn measure(reference: impl FnOnce() -> anyhow::Result<i64>) -> anyhow::Result<Offset> {
+    let before = realtime()?;
+    let reference = reference()?;
+    let after = realtime()?;
+    let uncertainty = (after - before) / 2;
+    Ok(Offset {
+        nanos: reference - (before + uncertainty),
+        uncertainty,
+    })
+}
+
+/// The clock ID of an open dynamic POSIX clock, see clock_getres(2)
+fn fd_clock(fd: RawFd) -> ClockId {
+    const CLOCKFD: clockid_t = 3;
+    ClockId::from_raw(((!fd) << 3) | CLOCKFD)
+}
+
+fn read_ptp(device: &Path) -> anyhow::Result<Offset> {
+    let file = File::open(device).with_context(|| format!("When opening {}", device.display()))?;
+    let clock = fd_clock(file.as_raw_fd());
+    measure(|| {
+        Ok(to_nanos(clock_gettime(clock).with_context(|| {
+            format!("When reading {}", device.display())
+        })?))
+    })
+}
+
+/// Parses the milliseconds since the epoch PowerShell printed
+fn parse_windows_time(output: &str) -> anyhow::Result<i64> {
+    let millis: i64 = output
+        .trim()
+        .parse()
+        .with_context(|| format!("When parsing the time from Windows ({:?})", output.trim()))?;
+    Ok(millis * 1_000_000)
+}
+
+fn ask_windows(powershell: &Path, timeout: Duration) -> anyhow::Result<i64> {
+    let mut command = Command::new(powershell);
+    command.args([
+        "-NoProfile",
+        "-NonInteractive",
+        "-Command",
+        "[DateTimeOffset]::UtcNow.ToUnixTimeMilliseconds()",
+    ]);
+    use_live_socket(&mut command);
+    let output = run_with_timeout(&mut command, timeout)?;
+    if !output.status.success() {
+        bail!(
+            "PowerShell failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_windows_time(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// What to do about `offset`. Offsets the measurement can't tell from zero are left alone.
+fn correction(offset: Offset, slew_threshold: f64, step_threshold: f64) -> Correction {
+    let size = offset.nanos.abs();
+    if size <= offset.uncertainty || seconds(size) < slew_threshold {
+        Correction::None
+    } else if seconds(size) < step_threshold {
+        Correction::Slew(offset.nanos)
+    } else {
+        Correction::Step(offset.nanos)
+    }
+}
+
+/// Lets the kernel speed up or slow down the clock until it caught up by `nanos`, see adjtimex(2)
+fn slew(nanos: i64) -> anyhow::Result<()> {
+    // SAFETY: timex is plain data, all zeroes means "change nothing" except for the fields set below
+    let mut tx: timex = unsafe { MaybeUninit::zeroed().assume_init() };
+    tx.modes = ADJ_OFFSET_SINGLESHOT;
+    tx.offset = (nanos / 1000) as _;
+    // SAFETY: tx is a valid timex for the duration of the call
+    Errno::result(unsafe { adjtimex(&mut tx) }).context("When slewing the clock")?;
+    Ok(())
+}
+
+fn step(nanos: i64) -> anyhow::Result<()> {
+    // Read again, so the time it took to get here doesn't get lost
+    let now = realtime()?;
+    clock_settime(ClockId::CLOCK_REALTIME, from_nanos(now + nanos))
+        .context("When setting the clock")
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    if args.step_threshold < args.slew_threshold {
+        bail!("--step-threshold has to be at least --slew-threshold");
+    }
+
+    let offset = match args.source {
+        Source::Ptp => read_ptp(&args.ptp_device)?,
+        Source::Windows => {
+            measure(|| ask_windows(&args.powershell, Duration::from_secs(args.timeout)))?
+        }
+    };
+    let description = format!(
+        "The clock is {:.3}s {} Windows (±{:.3}s)",
+        seconds(offset.nanos.abs()),
+        if offset.nanos > 0 {
+            "behind"
+        } else {
+            "ahead of"
+        },
+        seconds(offset.uncertainty)
+    );
+    if args.check {
+        println!("{}", description);
+        return Ok(());
+    }
+
+    match correction(offset, args.slew_threshold, args.step_threshold) {
+        Correction::None => {}
+        Correction::Slew(nanos) => {
+            slew(nanos)?;
+            println!("{}, slewing it", description);
+        }
+        Correction::Step(nanos) => {
+            step(nanos)?;
+            println!("{}, setting it", description);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offset(seconds: f64, uncertainty: f64) -> Offset {
+        Offset {
+            nanos: (seconds * NANOS_PER_SEC as f64) as i64,
+            uncertainty: (uncertainty * NANOS_PER_SEC as f64) as i64,
+        }
+    }
+
+    #[test]
+    fn picks_corrections() {
+        assert_eq!(correction(offset(0.01, 0.0), 0.05, 1.0), Correction::None);
+        assert_eq!(
+            correction(offset(-0.2, 0.01), 0.05, 1.0),
+            Correction::Slew(-200_000_000)
+        );
+        assert_eq!(
+            correction(offset(90.0, 0.5), 0.05, 1.0),
+            Correction::Step(90 * NANOS_PER_SEC)
+        );
+        // A slow PowerShell can't tell 0.3s from nothing
+        assert_eq!(correction(offset(0.3, 0.8), 0.05, 1.0), Correction::None);
+    }
+
+    #[test]
+    fn converts_negative_times() {
+        assert_eq!(to_nanos(from_nanos(-1_500_000_000)), -1_500_000_000);
+        assert_eq!(from_nanos(-1_500_000_000).tv_nsec(), 500_000_000);
+    }
+
+    #[test]
+    fn measures_halfway() {
+        let offset = measure(|| Ok(realtime()? + 5 * NANOS_PER_SEC)).unwrap();
+        assert!((offset.nanos - 5 * NANOS_PER_SEC).abs() <= 2 * offset.uncertainty + 1_000_000);
+    }
+
+    #[test]
+    fn parses_windows_time() {
+        assert_eq!(
+            parse_windows_time("1760400000123\r\n").unwrap(),
+            1_760_400_000_123_000_000
+        );
+        assert!(parse_windows_time("").is_err());
+    }
+
+    #[test]
+    fn builds_fd_clock_ids() {
+        assert_eq!(fd_clock(3).as_raw(), -29);
+    }
+}
@@ -0,0 +1,547 @@
+use anyhow::{anyhow, bail, Context};
+use std::collections::{HashMap, HashSet};
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::retry::RetryPolicy;
+
+/// Rendered by the `wsl.shim` NixOS options.
+/// This is read before activation, so it always comes from the previously activated generation.
+pub const CONFIG_PATH: &str = "/etc/nixos-wsl/shim.conf";
+
+/// Mount options for the /dev/shm tmpfs
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DevShmConfig {
+    pub size: Option<String>,
+    pub mode: Option<u32>,
+    pub huge: Option<String>,
+}
+
+impl DevShmConfig {
+    /// tmpfs options that can be applied to an already mounted /dev/shm.
+    /// The mode is left out, because tmpfs ignores it on remount.
+    pub fn remount_options(&self) -> Option<String> {
+        let options: Vec<String> = [("size", &self.size), ("huge", &self.huge)]
+            .iter()
+            .filter_map(|(key, value)| value.as_ref().map(|v| format!("{}={}", key, v)))
+            .collect();
+        if options.is_empty() {
+            None
+        } else {
+            Some(options.join(","))
+        }
+    }
+}
+
+/// Hugepages reserved before anything else gets a chance to fragment memory
+#[derive(Debug, Clone, PartialEq)]
+pub struct HugepagesConfig {
+    /// Number of pages to reserve, 0 disables the step
+    pub pages: u64,
+    /// Page size in KiB, the kernel default if unset
+    pub page_size_kb: Option<u64>,
+    /// Where to mount hugetlbfs
+    pub mount_point: PathBuf,
+}
+
+impl Default for HugepagesConfig {
+    fn default() -> Self {
+        HugepagesConfig {
+            pages: 0,
+            page_size_kb: None,
+            mount_point: PathBuf::from("/dev/hugepages"),
+        }
+    }
+}
+
+/// What to do when the activation script doesn't finish in time
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OnTimeout {
+    /// Start systemd anyway
+    Continue,
+    /// Treat it as a failed activation, i.e. fall back to an older generation
+    #[default]
+    Recover,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActivationConfig {
+    /// None means waiting forever
+    pub timeout: Option<Duration>,
+    pub on_timeout: OnTimeout,
+    /// How many activation logs to keep under /var/log/nixos-wsl, 0 turns them off
+    pub keep_logs: usize,
+}
+
+impl Default for ActivationConfig {
+    fn default() -> Self {
+        ActivationConfig {
+            timeout: Some(Duration::from_secs(300)),
+            on_timeout: OnTimeout::default(),
+            keep_logs: 10,
+        }
+    }
+}
+
+/// What happens when a boot step keeps failing, instead of the step's built-in policy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnFailure {
+    /// Try once and boot anyway
+    Skip,
+    /// Retry transient errors a few times, then boot anyway
+    Retry,
+    /// Abort the boot. For activation, that means falling back to an older generation.
+    Fatal,
+}
+
+impl OnFailure {
+    pub fn apply(self, policy: RetryPolicy) -> RetryPolicy {
+        match self {
+            OnFailure::Skip => RetryPolicy {
+                attempts: 1,
+                backoff: Duration::ZERO,
+                fatal: false,
+            },
+            OnFailure::Retry => RetryPolicy {
+                attempts: policy.attempts.max(5),
+                backoff: policy.backoff.max(Duration::from_millis(50)),
+                fatal: false,
+            },
+            OnFailure::Fatal => RetryPolicy {
+                fatal: true,
+                ..policy
+            },
+        }
+    }
+}
+
+/// The boot menu the shim can show before picking a generation
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootMenuConfig {
+    /// The nixos-wsl-boot-menu binary, the menu can't be shown without it
+    pub path: Option<PathBuf>,
+    /// None means waiting forever
+    pub timeout: Option<Duration>,
+}
+
+impl Default for BootMenuConfig {
+    fn default() -> Self {
+        BootMenuConfig {
+            path: None,
+            timeout: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+/// Provisioning a freshly imported distro
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FirstBootConfig {
+    /// Run after activation as long as the provisioning marker is missing
+    pub path: Option<PathBuf>,
+}
+
+/// How the real systemd is started once the boot steps are done
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SystemdConfig {
+    /// Executable to use instead of the generation's own systemd
+    pub path: Option<PathBuf>,
+    /// Passed to systemd before the arguments the shim itself was started with
+    pub extra_args: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ShimConfig {
+    pub dev_shm: DevShmConfig,
+    pub hugepages: HugepagesConfig,
+    pub activation: ActivationConfig,
+    pub boot_menu: BootMenuConfig,
+    pub first_boot: FirstBootConfig,
+    pub systemd: SystemdConfig,
+    /// Boot steps turned off in the `[steps]` section
+    pub disabled_steps: HashSet<String>,
+    /// Failure policies set in the `[step-policy]` section
+    pub step_policies: HashMap<String, OnFailure>,
+}
+
+impl ShimConfig {
+    /// Loads the config, falling back to the defaults if there is none
+    pub fn load(path: &Path) -> anyhow::Result<ShimConfig> {
+        match read_to_string(path) {
+            Ok(contents) => {
+                parse(&contents).with_context(|| format!("When parsing {}", path.display()))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(ShimConfig::default()),
+            Err(e) => Err(e).with_context(|| format!("When reading {}", path.display())),
+        }
+    }
+}
+
+/// Parses the INI subset that `lib.generators.toINI` produces
+fn parse_ini(contents: &str) -> anyhow::Result<HashMap<String, HashMap<String, String>>> {
+    let mut sections: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut current = None;
+
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let section = section.trim().to_string();
+            sections.entry(section.clone()).or_default();
+            current = Some(section);
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected key=value", idx + 1))?;
+        let section = current
+            .as_ref()
+            .ok_or_else(|| anyhow!("line {}: key outside of a section", idx + 1))?;
+        sections
+            .entry(section.clone())
+            .or_default()
+            .insert(key.trim().to_string(), value.trim().to_string());
+    }
+
+    Ok(sections)
+}
+
+fn parse_size(value: &str) -> anyhow::Result<String> {
+    let digits = value.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G', '%']);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        bail!("invalid size: {}", value);
+    }
+    if value.len() - digits.len() > 1 {
+        bail!("invalid size suffix: {}", value);
+    }
+    Ok(value.to_string())
+}
+
+fn parse_mode(value: &str) -> anyhow::Result<u32> {
+    let mode = u32::from_str_radix(value, 8).with_context(|| format!("invalid mode: {}", value))?;
+    if mode > 0o7777 {
+        bail!("invalid mode: {}", value);
+    }
+    Ok(mode)
+}
+
+fn parse_huge(value: &str) -> anyhow::Result<String> {
+    match value {
+        "never" | "always" | "within_size" | "advise" => Ok(value.to_string()),
+        _ => bail!("invalid huge option: {}", value),
+    }
+}
+
+/// Parses page sizes like `2M` or `1G` into KiB
+fn parse_page_size(value: &str) -> anyhow::Result<u64> {
+    let (digits, factor) = match value.char_indices().last() {
+        Some((idx, 'k' | 'K')) => (&value[..idx], 1),
+        Some((idx, 'm' | 'M')) => (&value[..idx], 1024),
+        Some((idx, 'g' | 'G')) => (&value[..idx], 1024 * 1024),
+        _ => bail!("invalid page size (expected e.g. 2M or 1G): {}", value),
+    };
+    let size: u64 = digits
+        .parse()
+        .with_context(|| format!("invalid page size: {}", value))?;
+    size.checked_mul(factor)
+        .ok_or_else(|| anyhow!("page size too large: {}", value))
+}
+
+fn parse_bool(value: &str) -> anyhow::Result<bool> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => bail!("invalid boolean (expected true or false): {}", value),
+    }
+}
+
+/// Timeouts in seconds, 0 turns the timeout off
+fn parse_timeout(value: &str) -> anyhow::Result<Option<Duration>> {
+    let seconds: u64 = value
+        .parse()
+        .with_context(|| format!("invalid timeout (expected seconds): {}", value))?;
+    Ok(Some(Duration::from_secs(seconds)).filter(|timeout| !timeout.is_zero()))
+}
+
+fn parse_on_timeout(value: &str) -> anyhow::Result<OnTimeout> {
+    match value {
+        "continue" => Ok(OnTimeout::Continue),
+        "recover" => Ok(OnTimeout::Recover),
+        _ => bail!(
+            "invalid on-timeout (expected continue or recover): {}",
+            value
+        ),
+    }
+}
+
+fn parse_on_failure(value: &str) -> anyhow::Result<OnFailure> {
+    match value {
+        "skip" => Ok(OnFailure::Skip),
+        "retry" => Ok(OnFailure::Retry),
+        "fatal" => Ok(OnFailure::Fatal),
+        _ => bail!(
+            "invalid step policy (expected skip, retry or fatal): {}",
+            value
+        ),
+    }
+}
+
+fn parse_absolute_path(value: &str) -> anyhow::Result<PathBuf> {
+    let path = PathBuf::from(value);
+    if !path.is_absolute() {
+        bail!("path must be absolute: {}", value);
+    }
+    Ok(path)
+}
+
+fn parse(contents: &str) -> anyhow::Result<ShimConfig> {
+    let sections = parse_ini(contents)?;
+    let mut config = ShimConfig::default();
+
+    for (name, entries) in &sections {
+        for (key, value) in entries {
+            match (name.as_str(), key.as_str()) {
+                ("dev-shm", "size") => config.dev_shm.size = Some(parse_size(value)?),
+                ("dev-shm", "mode") => config.dev_shm.mode = Some(parse_mode(value)?),
+                ("dev-shm", "huge") => config.dev_shm.huge = Some(parse_huge(value)?),
+                ("hugepages", "pages") => {
+                    config.hugepages.pages = value
+                        .parse()
+                        .with_context(|| format!("invalid number of hugepages: {}", value))?
+                }
+                ("hugepages", "page-size") => {
+                    config.hugepages.page_size_kb = Some(parse_page_size(value)?)
+                }
+                ("hugepages", "mount-point") => {
+                    config.hugepages.mount_point = parse_absolute_path(value)?
+                }
+                ("activation", "timeout") => config.activation.timeout = parse_timeout(value)?,
+                ("activation", "on-timeout") => {
+                    config.activation.on_timeout = parse_on_timeout(value)?
+                }
+                ("boot-menu", "path") => config.boot_menu.path = Some(parse_absolute_path(value)?),
+                ("boot-menu", "timeout") => config.boot_menu.timeout = parse_timeout(value)?,
+                ("first-boot", "path") => {
+                    config.first_boot.path = Some(parse_absolute_path(value)?)
+                }
+                ("activation", "keep-logs") => {
+                    config.activation.keep_logs = value
+                        .parse()
+                        .with_context(|| format!("invalid number of logs: {}", value))?
+                }
+                ("systemd", "path") => config.systemd.path = Some(parse_absolute_path(value)?),
+                // toINI can't render lists, so these are whitespace separated
+                ("systemd", "extra-args") => {
+                    config.systemd.extra_args = value.split_whitespace().map(String::from).collect()
+                }
+                ("steps", step) => {
+                    if !parse_bool(value)? {
+                        config.disabled_steps.insert(step.to_string());
+                    }
+                }
+                ("step-policy", step) => {
+                    config
+                        .step_policies
+                        .insert(step.to_string(), parse_on_failure(value)?);
+                }
+                _ => log::warn!("Ignoring unknown shim option {}.{}", name, key),
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_is_default() {
+        assert_eq!(parse("").unwrap(), ShimConfig::default());
+    }
+
+    #[test]
+    fn parses_dev_shm() {
+        let config = parse("[dev-shm]\nhuge=within_size\nmode=1777\nsize=8G\n").unwrap();
+        assert_eq!(
+            config.dev_shm,
+            DevShmConfig {
+                size: Some("8G".to_string()),
+                mode: Some(0o1777),
+                huge: Some("within_size".to_string()),
+            }
+        );
+        assert_eq!(
+            config.dev_shm.remount_options().as_deref(),
+            Some("size=8G,huge=within_size")
+        );
+    }
+
+    #[test]
+    fn ignores_comments_and_unknown_keys() {
+        let config = parse("# comment\n[dev-shm]\n; other comment\nfoo = bar\n").unwrap();
+        assert_eq!(config.dev_shm, DevShmConfig::default());
+        assert_eq!(config.dev_shm.remount_options(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(parse("[dev-shm]\nsize=lots\n").is_err());
+        assert!(parse("[dev-shm]\nsize=1GG\n").is_err());
+        assert!(parse("[dev-shm]\nmode=999\n").is_err());
+        assert!(parse("[dev-shm]\nhuge=maybe\n").is_err());
+    }
+
+    #[test]
+    fn parses_hugepages() {
+        let config = parse("[hugepages]\nmount-point=/mnt/huge\npage-size=1G\npages=4\n").unwrap();
+        assert_eq!(
+            config.hugepages,
+            HugepagesConfig {
+                pages: 4,
+                page_size_kb: Some(1024 * 1024),
+                mount_point: PathBuf::from("/mnt/huge"),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_hugepages() {
+        assert!(parse("[hugepages]\npages=-1\n").is_err());
+        assert!(parse("[hugepages]\npage-size=2\n").is_err());
+        assert!(parse("[hugepages]\npage-size=18446744073709551615G\n").is_err());
+        assert!(parse("[hugepages]\nmount-point=huge\n").is_err());
+    }
+
+    #[test]
+    fn parses_disabled_steps() {
+        let config = parse("[steps]\ndev-shm=true\nstore-ro=false\n").unwrap();
+        assert_eq!(
+            config.disabled_steps,
+            HashSet::from(["store-ro".to_string()])
+        );
+        assert!(parse("[steps]\nstore-ro=no\n").is_err());
+    }
+
+    #[test]
+    fn parses_step_policies() {
+        let config = parse("[step-policy]\nactivation=skip\nroot-shared=retry\n").unwrap();
+        assert_eq!(
+            config.step_policies,
+            HashMap::from([
+                ("activation".to_string(), OnFailure::Skip),
+                ("root-shared".to_string(), OnFailure::Retry),
+            ])
+        );
+        assert!(parse("[step-policy]\nstore-ro=ignore\n").is_err());
+    }
+
+    #[test]
+    fn applies_step_policies() {
+        let once = RetryPolicy::FATAL_ONCE;
+        assert_eq!(
+            OnFailure::Skip.apply(once),
+            RetryPolicy {
+                fatal: false,
+                ..once
+            }
+        );
+        let retried = OnFailure::Retry.apply(once);
+        assert_eq!((retried.attempts, retried.fatal), (5, false));
+        assert!(!retried.backoff.is_zero());
+        let fatal = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(10),
+            fatal: false,
+        };
+        assert_eq!(
+            OnFailure::Fatal.apply(fatal),
+            RetryPolicy {
+                fatal: true,
+                ..fatal
+            }
+        );
+    }
+
+    #[test]
+    fn parses_activation() {
+        let config = parse("[activation]\nkeep-logs=0\non-timeout=continue\ntimeout=60\n").unwrap();
+        assert_eq!(
+            config.activation,
+            ActivationConfig {
+                timeout: Some(Duration::from_secs(60)),
+                on_timeout: OnTimeout::Continue,
+                keep_logs: 0,
+            }
+        );
+        assert_eq!(
+            parse("[activation]\ntimeout=0\n")
+                .unwrap()
+                .activation
+                .timeout,
+            None
+        );
+        assert!(parse("[activation]\ntimeout=5m\n").is_err());
+        assert!(parse("[activation]\non-timeout=panic\n").is_err());
+    }
+
+    #[test]
+    fn parses_boot_menu() {
+        let config = parse("[boot-menu]\npath=/bin/nixos-wsl-boot-menu\ntimeout=0\n").unwrap();
+        assert_eq!(
+            config.boot_menu,
+            BootMenuConfig {
+                path: Some(PathBuf::from("/bin/nixos-wsl-boot-menu")),
+                timeout: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_first_boot() {
+        let config = parse("[first-boot]\npath=/bin/nixos-wsl-first-boot\n").unwrap();
+        assert_eq!(
+            config.first_boot.path,
+            Some(PathBuf::from("/bin/nixos-wsl-first-boot"))
+        );
+        assert!(parse("[first-boot]\npath=first-boot\n").is_err());
+    }
+
+    #[test]
+    fn parses_systemd() {
+        let config = parse(
+            "[systemd]\nextra-args=--log-level=debug  --show-status=yes\npath=/bin/systemd\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.systemd,
+            SystemdConfig {
+                path: Some(PathBuf::from("/bin/systemd")),
+                extra_args: vec![
+                    "--log-level=debug".to_string(),
+                    "--show-status=yes".to_string()
+                ],
+            }
+        );
+        assert!(parse("[systemd]\npath=systemd\n").is_err());
+    }
+
+    #[test]
+    fn rejects_keys_outside_sections() {
+        assert!(parse("size=1G\n").is_err());
+    }
+
+    #[test]
+    fn missing_file_is_default() {
+        assert_eq!(
+            ShimConfig::load(Path::new("/nonexistent/shim.conf")).unwrap(),
+            ShimConfig::default()
+        );
+    }
+}
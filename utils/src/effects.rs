@@ -0,0 +1,153 @@
+//! Everything the boot steps change on the system goes through here, so `--dry-run` can print it instead
+
+use nix::mount::MsFlags;
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Prints what would be done, returning whether to skip doing it
+pub fn dry_run(what: impl FnOnce() -> String) -> bool {
+    if is_dry_run() {
+        println!("would {}", what());
+        true
+    } else {
+        false
+    }
+}
+
+const FLAG_NAMES: &[(MsFlags, &str)] = &[
+    (MsFlags::MS_RDONLY, "ro"),
+    (MsFlags::MS_NOSUID, "nosuid"),
+    (MsFlags::MS_NODEV, "nodev"),
+    (MsFlags::MS_NOEXEC, "noexec"),
+    (MsFlags::MS_REMOUNT, "remount"),
+    (MsFlags::MS_BIND, "bind"),
+    (MsFlags::MS_MOVE, "move"),
+    (MsFlags::MS_REC, "rec"),
+    (MsFlags::MS_SHARED, "shared"),
+];
+
+/// Renders a mount call like the corresponding mount(8) command line
+pub fn describe_mount(
+    source: Option<&Path>,
+    target: &Path,
+    fstype: Option<&str>,
+    flags: MsFlags,
+    data: Option<&str>,
+) -> String {
+    let mut options: Vec<&str> = FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .map(|(_, name)| *name)
+        .collect();
+    options.extend(data);
+
+    let mut command = String::from("mount");
+    if let Some(fstype) = fstype {
+        command.push_str(&format!(" -t {}", fstype));
+    }
+    if !options.is_empty() {
+        command.push_str(&format!(" -o {}", options.join(",")));
+    }
+    if let Some(source) = source {
+        command.push_str(&format!(" {}", source.display()));
+    }
+    command.push_str(&format!(" {}", target.display()));
+    command
+}
+
+pub fn mount(
+    source: Option<impl AsRef<Path>>,
+    target: impl AsRef<Path>,
+    fstype: Option<&str>,
+    flags: MsFlags,
+    data: Option<&str>,
+) -> nix::Result<()> {
+    let source = source.as_ref().map(|s| s.as_ref());
+    let target = target.as_ref();
+    if dry_run(|| describe_mount(source, target, fstype, flags, data)) {
+        return Ok(());
+    }
+    nix::mount::mount(source, target, fstype, flags, data)
+}
+
+pub fn remove_file(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    if dry_run(|| format!("rm {}", path.display())) {
+        return Ok(());
+    }
+    fs::remove_file(path)
+}
+
+pub fn remove_dir_all(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    if dry_run(|| format!("rm -r {}", path.display())) {
+        return Ok(());
+    }
+    fs::remove_dir_all(path)
+}
+
+pub fn create_dir_all(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    if dry_run(|| format!("mkdir -p {}", path.display())) {
+        return Ok(());
+    }
+    fs::create_dir_all(path)
+}
+
+pub fn set_mode(path: impl AsRef<Path>, mode: u32) -> io::Result<()> {
+    let path = path.as_ref();
+    if dry_run(|| format!("chmod {:o} {}", mode, path.display())) {
+        return Ok(());
+    }
+    fs::set_permissions(path, Permissions::from_mode(mode))
+}
+
+pub fn write(path: impl AsRef<Path>, contents: &str) -> io::Result<()> {
+    let path = path.as_ref();
+    if dry_run(|| format!("write {:?} to {}", contents, path.display())) {
+        return Ok(());
+    }
+    fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_mounts() {
+        assert_eq!(
+            describe_mount(
+                Some(Path::new("tmpfs")),
+                Path::new("/dev/shm"),
+                Some("tmpfs"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                Some("mode=1777,size=50%")
+            ),
+            "mount -t tmpfs -o nosuid,nodev,mode=1777,size=50% tmpfs /dev/shm"
+        );
+        assert_eq!(
+            describe_mount(
+                None,
+                Path::new("/"),
+                None,
+                MsFlags::MS_REC | MsFlags::MS_SHARED,
+                None
+            ),
+            "mount -o rec,shared /"
+        );
+    }
+}
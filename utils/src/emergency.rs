@@ -0,0 +1,151 @@
+use anyhow::{anyhow, bail, Context};
+use nix::errno::Errno;
+use nix::sys::wait::wait;
+use nix::unistd::isatty;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, ExitStatus};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Shells to try, in order. The busybox copy from `wsl.shim.copyToSbin` doesn't need the store at all,
+/// /bin/sh only exists if a previous activation got far enough to create it.
+const SHELLS: &[(&str, &[&str])] = &[
+    ("/sbin/nixos-wsl-busybox", &["sh"]),
+    ("/bin/sh", &[]),
+    ("/run/current-system/sw/bin/sh", &[]),
+];
+
+/// A shell that exits faster than this most likely has no usable console, so it isn't restarted
+const MIN_SHELL_RUNTIME: Duration = Duration::from_secs(1);
+
+fn banner(err: &anyhow::Error) -> String {
+    format!(
+        "\
+NixOS-WSL failed to boot:
+{:?}
+
+Starting an emergency shell. Some places to start:
+  dmesg                                             the full boot log
+  ls /nix/var/nix/profiles                          available system generations
+  /nix/var/nix/profiles/system-<N>-link/activate    activate an older generation by hand
+Exiting the shell restarts it. Run `wsl --terminate <distro>` on Windows to restart the distro.
+",
+        err
+    )
+}
+
+fn find_shell(
+    candidates: &'static [(&'static str, &'static [&'static str])],
+) -> Option<(&'static str, &'static [&'static str])> {
+    candidates
+        .iter()
+        .copied()
+        .find(|(path, _)| Path::new(path).exists())
+}
+
+/// Opens `path` if it is a terminal someone could type into
+fn open_terminal(path: &Path) -> anyhow::Result<File> {
+    let terminal = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("When opening {}", path.display()))?;
+    if !isatty(&terminal).unwrap_or(false) {
+        bail!("{} is not a terminal", path.display());
+    }
+    Ok(terminal)
+}
+
+/// The console, if WSL attached a terminal to it. Without one there is nobody to prompt,
+/// so the boot menu and the emergency shell are skipped instead of waiting for input.
+pub fn open_console() -> anyhow::Result<File> {
+    open_terminal(Path::new("/dev/console"))
+}
+
+fn spawn_shell(banner: &str) -> anyhow::Result<ExitStatus> {
+    let (shell, args) = find_shell(SHELLS).ok_or(anyhow!("No shell found"))?;
+
+    let mut console = open_console()?;
+    console
+        .write_all(banner.as_bytes())
+        .context("When writing to /dev/console")?;
+
+    log::warn!("Starting emergency shell {}...", shell);
+    Command::new(shell)
+        .args(args)
+        .env("PATH", "/run/current-system/sw/bin:/bin:/sbin")
+        .env("PS1", "(emergency) # ")
+        .stdin(console.try_clone().context("When duplicating console fd")?)
+        .stdout(console.try_clone().context("When duplicating console fd")?)
+        .stderr(console)
+        .status()
+        .with_context(|| format!("When running {}", shell))
+}
+
+/// Called when booting failed for good. Never returns, since PID 1 exiting tears down the whole distro.
+pub fn run(err: &anyhow::Error) -> ! {
+    let banner = banner(err);
+
+    loop {
+        let started = Instant::now();
+        match spawn_shell(&banner) {
+            Ok(status) if started.elapsed() >= MIN_SHELL_RUNTIME => {
+                log::warn!("Emergency shell exited ({}), restarting it...", status);
+            }
+            Ok(status) => {
+                log::error!("Emergency shell exited immediately ({})", status);
+                break;
+            }
+            Err(e) => {
+                log::error!("Could not start an emergency shell: {:?}", e);
+                break;
+            }
+        }
+    }
+
+    log::error!("Nothing left to do, idling");
+    loop {
+        // Keep reaping whatever got reparented to us
+        match wait() {
+            Ok(_) => {}
+            // Nothing left to wait for right now
+            Err(Errno::ECHILD) => sleep(Duration::from_secs(60)),
+            // Retrying at once would keep PID 1 busy on EINTR and friends
+            Err(_) => sleep(Duration::from_secs(1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn banner_contains_error() {
+        let banner = banner(&anyhow!("Activation exited with status 1"));
+        assert!(banner.starts_with("NixOS-WSL failed to boot:\nActivation exited with status 1\n"));
+    }
+
+    #[test]
+    fn needs_a_terminal() {
+        let dir = scratch_dir("emergency-console");
+        let file = dir.join("console");
+        std::fs::write(&file, "").unwrap();
+        assert!(open_terminal(&file)
+            .unwrap_err()
+            .to_string()
+            .contains("not a terminal"));
+        assert!(open_terminal(&dir.join("missing")).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn finds_first_existing_shell() {
+        const CANDIDATES: &[(&str, &[&str])] = &[("/nonexistent/sh", &["sh"]), ("/", &[])];
+        assert_eq!(find_shell(CANDIDATES), Some(("/", &[][..])));
+        assert_eq!(find_shell(&CANDIDATES[..1]), None);
+    }
+}
@@ -0,0 +1,485 @@
+#[cfg(test)]
+mod test_util;
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use std::fs::{canonicalize, read_dir, read_link, remove_file, symlink_metadata, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+
+/// Writes a tarball `wsl --import` accepts, from the closure of the running system.
+/// Unlike the tarball builder this doesn't install the system into a new root, the first boot activates it.
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-export", version = build_info::VERSION)]
+struct Args {
+    /// Where to write the tarball, gzipped if the name ends with .gz
+    output: PathBuf,
+
+    #[arg(long, default_value = "/run/current-system")]
+    system: PathBuf,
+
+    /// Copied to /etc/nixos in the tarball
+    #[arg(long, default_value = "/etc/nixos")]
+    config: PathBuf,
+
+    /// Leave /etc/nixos out
+    #[arg(long)]
+    no_config: bool,
+
+    #[arg(long, default_value = "nix-store")]
+    nix_store: PathBuf,
+
+    #[arg(long, default_value = "gzip")]
+    gzip: PathBuf,
+}
+
+const BLOCK: usize = 512;
+
+/// Every entry gets this mtime, so exporting the same system twice gives the same tarball
+const MTIME: u64 = 1;
+
+/// The nixbld group, which owns /nix/store on NixOS
+const NIXBLD_GID: u32 = 30000;
+
+/// Loaded into the Nix database by an activation script on the first boot, like stage 2 does for disk images
+const REGISTRATION: &str = "nix-path-registration";
+
+/// Files WSL reads before the distro boots, so they can't wait for activation to create them
+const WSL_FILES: [&str; 3] = ["wsl.conf", "wsl-distribution.conf", "nixos.ico"];
+
+/// Writes a field of a tar header as a NUL terminated octal number
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(digits.as_bytes());
+    field[width] = 0;
+}
+
+/// A `<length> <key>=<value>\n` record of a pax extended header, where the length counts itself
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + rest.to_string().len();
+    if length.to_string().len() > rest.to_string().len() {
+        length += 1;
+    }
+    let mut record = format!("{} {}=", length, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+/// A ustar header. Names that don't fit are cut off, the pax header before it has them in full.
+fn header(name: &[u8], kind: u8, mode: u32, gid: u32, size: u64, link: &[u8]) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    let name = &name[..name.len().min(100)];
+    block[..name.len()].copy_from_slice(name);
+    octal(&mut block[100..108], mode as u64);
+    octal(&mut block[108..116], 0);
+    octal(&mut block[116..124], gid as u64);
+    octal(&mut block[124..136], size.min(0o77777777777));
+    octal(&mut block[136..148], MTIME);
+    block[156] = kind;
+    let link = &link[..link.len().min(100)];
+    block[157..157 + link.len()].copy_from_slice(link);
+    block[257..265].copy_from_slice(b"ustar\x0000");
+
+    // Summed with the checksum field itself filled with spaces
+    block[148..156].fill(b' ');
+    let sum: u32 = block.iter().map(|&b| b as u32).sum();
+    block[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    block
+}
+
+struct TarWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let rest = (size % BLOCK as u64) as usize;
+        if rest > 0 {
+            self.out.write_all(&[0u8; BLOCK][rest..])?;
+        }
+        Ok(())
+    }
+
+    fn entry(
+        &mut self,
+        name: &[u8],
+        kind: u8,
+        mode: u32,
+        gid: u32,
+        size: u64,
+        link: &[u8],
+    ) -> io::Result<()> {
+        let mut pax = Vec::new();
+        if name.len() > 100 {
+            pax.extend(pax_record("path", name));
+        }
+        if link.len() > 100 {
+            pax.extend(pax_record("linkpath", link));
+        }
+        if size > 0o77777777777 {
+            pax.extend(pax_record("size", size.to_string().as_bytes()));
+        }
+        if !pax.is_empty() {
+            let mut pax_name = b"PaxHeaders/".to_vec();
+            pax_name.extend_from_slice(
+                name.rsplit(|&b| b == b'/')
+                    .find(|s| !s.is_empty())
+                    .unwrap_or(b""),
+            );
+            self.out
+                .write_all(&header(&pax_name, b'x', 0o644, 0, pax.len() as u64, b""))?;
+            self.out.write_all(&pax)?;
+            self.pad(pax.len() as u64)?;
+        }
+        self.out
+            .write_all(&header(name, kind, mode, gid, size, link))
+    }
+
+    fn dir(&mut self, name: &[u8], mode: u32, gid: u32) -> io::Result<()> {
+        let mut name = name.to_vec();
+        name.push(b'/');
+        self.entry(&name, b'5', mode, gid, 0, b"")
+    }
+
+    fn symlink(&mut self, name: &[u8], target: &[u8]) -> io::Result<()> {
+        self.entry(name, b'2', 0o777, 0, 0, target)
+    }
+
+    fn file(
+        &mut self,
+        name: &[u8],
+        mode: u32,
+        size: u64,
+        contents: impl Read,
+    ) -> anyhow::Result<()> {
+        self.entry(name, b'0', mode, 0, size, b"")?;
+        let copied = io::copy(&mut contents.take(size), &mut self.out)?;
+        if copied != size {
+            bail!(
+                "{} changed while it was being written",
+                String::from_utf8_lossy(name)
+            );
+        }
+        self.pad(size)?;
+        Ok(())
+    }
+
+    /// Writes the two empty blocks that end the archive
+    fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0u8; 2 * BLOCK])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Adds `path` as `name`, with everything below it. Entries are sorted, and hard links become copies.
+fn add_tree<W: Write>(tar: &mut TarWriter<W>, path: &Path, name: &[u8]) -> anyhow::Result<()> {
+    let metadata =
+        symlink_metadata(path).with_context(|| format!("When reading {}", path.display()))?;
+    let file_type = metadata.file_type();
+    let mode = metadata.mode() & 0o7777;
+    if file_type.is_symlink() {
+        let target = read_link(path).with_context(|| format!("When reading {}", path.display()))?;
+        tar.symlink(name, target.as_os_str().as_bytes())?;
+    } else if file_type.is_dir() {
+        tar.dir(name, mode, 0)?;
+        let mut entries: Vec<_> = read_dir(path)
+            .with_context(|| format!("When reading {}", path.display()))?
+            .collect::<io::Result<_>>()
+            .with_context(|| format!("When reading {}", path.display()))?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let mut child = name.to_vec();
+            child.push(b'/');
+            child.extend_from_slice(entry.file_name().as_bytes());
+            add_tree(tar, &entry.path(), &child)?;
+        }
+    } else if file_type.is_file() {
+        let file = File::open(path).with_context(|| format!("When opening {}", path.display()))?;
+        tar.file(name, mode, metadata.len(), file)
+            .with_context(|| format!("When adding {}", path.display()))?;
+    } else {
+        bail!("{} is not a file, directory or symlink", path.display());
+    }
+    Ok(())
+}
+
+/// Strips the leading slash, tar entries are relative to the root of the distro
+fn entry_name(path: &Path) -> &[u8] {
+    let bytes = path.as_os_str().as_bytes();
+    bytes.strip_prefix(b"/").unwrap_or(bytes)
+}
+
+fn nix_store(nix_store: &Path, args: &[&[u8]]) -> anyhow::Result<Vec<u8>> {
+    let output = Command::new(nix_store)
+        .args(args.iter().map(|arg| std::ffi::OsStr::from_bytes(arg)))
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("When running {}", nix_store.display()))?;
+    if !output.status.success() {
+        bail!(
+            "nix-store {} failed ({}): {}",
+            String::from_utf8_lossy(args[0]),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// The store paths in `nix-store --query --requisites` output, sorted
+fn parse_closure(output: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = output
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("/nix/store/"))
+        .map(PathBuf::from)
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// The shim and the rescue busybox, which WSL starts before activation could set up /sbin
+fn add_sbin<W: Write>(tar: &mut TarWriter<W>, closure: &[PathBuf]) -> anyhow::Result<()> {
+    tar.dir(b"sbin", 0o755, 0)?;
+    for name in ["init", "nixos-wsl-busybox"] {
+        let path = Path::new("/sbin").join(name);
+        match read_link(&path) {
+            Ok(target) => {
+                if !closure
+                    .iter()
+                    .any(|store_path| target.starts_with(store_path))
+                {
+                    bail!(
+                        "{} points to {}, which is not part of the system",
+                        path.display(),
+                        target.display()
+                    );
+                }
+                tar.symlink(entry_name(&path), target.as_os_str().as_bytes())?;
+            }
+            // A copy, see wsl.shim.copyToSbin
+            Err(e) if e.raw_os_error() == Some(nix::libc::EINVAL) => {
+                add_tree(tar, &path, entry_name(&path))?
+            }
+            Err(_) if name != "init" => {}
+            Err(e) => return Err(e).context("When reading /sbin/init"),
+        }
+    }
+    Ok(())
+}
+
+fn export<W: Write>(args: &Args, system: &Path, out: W) -> anyhow::Result<W> {
+    let closure = parse_closure(&String::from_utf8_lossy(&nix_store(
+        &args.nix_store,
+        &[b"--query", b"--requisites", system.as_os_str().as_bytes()],
+    )?));
+    if closure.is_empty() {
+        bail!("nix-store didn't list the closure of {}", system.display());
+    }
+    let mut dump_db = vec![b"--dump-db".as_slice()];
+    dump_db.extend(closure.iter().map(|path| path.as_os_str().as_bytes()));
+    let registration = nix_store(&args.nix_store, &dump_db)?;
+
+    let mut tar = TarWriter { out };
+    for (name, mode) in [
+        ("dev", 0o755),
+        ("proc", 0o555),
+        ("run", 0o755),
+        ("sys", 0o555),
+        ("tmp", 0o1777),
+        ("var", 0o755),
+        ("root", 0o700),
+        ("etc", 0o755),
+    ] {
+        tar.dir(name.as_bytes(), mode, 0)?;
+    }
+    for name in WSL_FILES {
+        let path = Path::new("/etc").join(name);
+        if path.exists() {
+            let file =
+                File::open(&path).with_context(|| format!("When opening {}", path.display()))?;
+            let size = file.metadata()?.len();
+            tar.file(entry_name(&path), 0o644, size, file)?;
+        } else {
+            eprintln!("{} doesn't exist, leaving it out", path.display());
+        }
+    }
+    if !args.no_config {
+        add_tree(&mut tar, &args.config, b"etc/nixos")?;
+    }
+    add_sbin(&mut tar, &closure)?;
+    tar.file(
+        REGISTRATION.as_bytes(),
+        0o644,
+        registration.len() as u64,
+        registration.as_slice(),
+    )?;
+
+    tar.dir(b"nix", 0o755, 0)?;
+    tar.dir(b"nix/var", 0o755, 0)?;
+    tar.dir(b"nix/var/nix", 0o755, 0)?;
+    tar.dir(b"nix/var/nix/profiles", 0o755, 0)?;
+    tar.symlink(b"nix/var/nix/profiles/system", b"system-1-link")?;
+    tar.symlink(
+        b"nix/var/nix/profiles/system-1-link",
+        system.as_os_str().as_bytes(),
+    )?;
+    tar.dir(b"nix/store", 0o1775, NIXBLD_GID)?;
+    for path in &closure {
+        add_tree(&mut tar, path, entry_name(path))?;
+    }
+    Ok(tar.finish()?)
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    let system = canonicalize(&args.system)
+        .with_context(|| format!("When resolving {}", args.system.display()))?;
+    if !system.join("activate").exists() {
+        bail!("{} is not a NixOS system", system.display());
+    }
+
+    let file = File::create(&args.output)
+        .with_context(|| format!("When creating {}", args.output.display()))?;
+    let result = if args.output.extension().map_or(false, |ext| ext == "gz") {
+        // -n leaves the name and time out, so the output stays reproducible
+        let mut gzip = Command::new(&args.gzip)
+            .args(["-n", "-c"])
+            .stdin(Stdio::piped())
+            .stdout(file)
+            .spawn()
+            .with_context(|| format!("When starting {}", args.gzip.display()))?;
+        let stdin = gzip.stdin.take().expect("stdin is piped");
+        let result = export(&args, &system, BufWriter::new(stdin)).map(drop);
+        let status = gzip.wait().context("When waiting for gzip")?;
+        result.and_then(|()| {
+            if status.success() {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("gzip failed with {}", status))
+            }
+        })
+    } else {
+        export(&args, &system, BufWriter::new(file)).map(drop)
+    };
+    if result.is_err() {
+        let _ = remove_file(&args.output);
+    }
+    result?;
+    eprintln!(
+        "Exported {} to {}, import it with `wsl --import`",
+        system.display(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::os::unix::fs::symlink;
+
+    fn field(block: &[u8], range: std::ops::Range<usize>) -> String {
+        String::from_utf8_lossy(&block[range])
+            .trim_end_matches('\0')
+            .to_string()
+    }
+
+    #[test]
+    fn writes_ustar_headers() {
+        let block = header(b"etc/wsl.conf", b'0', 0o644, 0, 1234, b"");
+        assert_eq!(field(&block, 0..100), "etc/wsl.conf");
+        assert_eq!(field(&block, 100..108), "0000644");
+        assert_eq!(field(&block, 124..136), "00000002322");
+        assert_eq!(&block[257..265], b"ustar\x0000");
+        let mut unsummed = block;
+        unsummed[148..156].fill(b' ');
+        let sum: u32 = unsummed.iter().map(|&b| b as u32).sum();
+        assert_eq!(field(&block, 148..154), format!("{:06o}", sum));
+    }
+
+    #[test]
+    fn counts_pax_record_lengths() {
+        assert_eq!(pax_record("path", b"a"), b"9 path=a\n");
+        // 96 + 3 digits would be 99, but 100 takes one more digit
+        let value = vec![b'x'; 91];
+        let record = pax_record("path", &value);
+        assert_eq!(record.len(), 101);
+        assert!(record.starts_with(b"101 path="));
+    }
+
+    #[test]
+    fn writes_long_names_as_pax() {
+        let name = format!("nix/store/{}-system/sw", "a".repeat(100));
+        let mut tar = TarWriter { out: Vec::new() };
+        tar.symlink(name.as_bytes(), b"/nix/store/b").unwrap();
+        let bytes = tar.finish().unwrap();
+        // pax header, its records, the entry itself and the end of the archive
+        assert_eq!(bytes.len(), 5 * BLOCK);
+        assert_eq!(bytes[156], b'x');
+        assert!(bytes[BLOCK..2 * BLOCK]
+            .starts_with(format!("{} path={}\n", name.len() + 10, name).as_bytes()));
+        assert_eq!(bytes[2 * BLOCK + 156], b'2');
+        assert!(bytes[3 * BLOCK..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn adds_trees_in_order() {
+        let dir = scratch_dir("export-tree");
+        create_dir_all(dir.join("b")).unwrap();
+        write(dir.join("b/file"), "hello").unwrap();
+        write(dir.join("a"), "").unwrap();
+        symlink("b/file", dir.join("c")).unwrap();
+
+        let mut tar = TarWriter { out: Vec::new() };
+        add_tree(&mut tar, &dir, b"root").unwrap();
+        let bytes = tar.finish().unwrap();
+        let names: Vec<String> = bytes
+            .chunks(BLOCK)
+            .filter(|block| &block[257..262] == b"ustar")
+            .map(|block| field(block, 0..100))
+            .collect();
+        assert_eq!(
+            names,
+            ["root/", "root/a", "root/b/", "root/b/file", "root/c"]
+        );
+        // Header, one block of contents
+        let file = bytes
+            .chunks(BLOCK)
+            .position(|block| field(block, 0..100) == "root/b/file")
+            .unwrap();
+        assert!(bytes[(file + 1) * BLOCK..].starts_with(b"hello\0"));
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parses_closures() {
+        assert_eq!(
+            parse_closure("/nix/store/b-system\n/nix/store/a-glibc\n\n"),
+            vec![
+                PathBuf::from("/nix/store/a-glibc"),
+                PathBuf::from("/nix/store/b-system")
+            ]
+        );
+    }
+}
@@ -0,0 +1,467 @@
+#[cfg(test)]
+mod test_util;
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use nix::unistd::User;
+use std::env;
+use std::fs::{
+    copy, create_dir_all, read_dir, read_to_string, remove_dir_all, rename, set_permissions, write,
+    Permissions,
+};
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+use nixos_wsl_utils::quote::shell_quote;
+
+/// Provisions a freshly imported distro from files left for it on the Windows side, so importing needs no manual steps.
+/// The shim runs this after activation until the marker exists. What needs the network or interop is written to the
+/// pending file and done by the nixos-wsl-first-boot service.
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-first-boot", version = build_info::VERSION)]
+struct Args {
+    /// The provisioning directory, instead of the newest .nixos-wsl in the home directory of a Windows user
+    #[arg(long)]
+    source: Option<PathBuf>,
+
+    /// Where WSL mounts the Windows drives
+    #[arg(long, default_value = "/mnt")]
+    automount_root: PathBuf,
+
+    #[arg(long, default_value = "/etc/nixos")]
+    config_dir: PathBuf,
+
+    /// Written once provisioning is done, the shim doesn't run this again after that
+    #[arg(long, default_value = "/var/lib/nixos-wsl/provisioned")]
+    marker: PathBuf,
+
+    /// Where the work left for the service goes, as shell variables
+    #[arg(long, default_value = "/var/lib/nixos-wsl/first-boot")]
+    pending: PathBuf,
+
+    #[arg(long, default_value = "/run/current-system/sw/bin/useradd")]
+    useradd: PathBuf,
+}
+
+/// The contents of provision.conf
+#[derive(Debug, PartialEq)]
+struct Provision {
+    user: Option<String>,
+    uid: Option<u32>,
+    groups: Vec<String>,
+    /// Built with `nixos-rebuild boot --flake` instead of /etc/nixos
+    flake: Option<String>,
+}
+
+impl Default for Provision {
+    fn default() -> Self {
+        Provision {
+            user: None,
+            uid: None,
+            groups: vec!["wheel".to_string()],
+            flake: None,
+        }
+    }
+}
+
+/// What useradd accepts by default, see NAME_REGEX in login.defs(5)
+fn valid_user_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    name.len() <= 32
+        && chars
+            .next()
+            .map_or(false, |c| c.is_ascii_lowercase() || c == '_')
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+/// Parses `key=value` lines, `#` starts a comment line
+fn parse_provision(contents: &str) -> anyhow::Result<Provision> {
+    let mut provision = Provision::default();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("line {}: expected key=value", number + 1))?;
+        let value = value.trim();
+        match key.trim() {
+            "user" => {
+                if !valid_user_name(value) {
+                    bail!("line {}: {:?} is not a valid user name", number + 1, value);
+                }
+                provision.user = Some(value.to_string());
+            }
+            "uid" => {
+                let uid: u32 = value
+                    .parse()
+                    .with_context(|| format!("line {}: invalid UID {:?}", number + 1, value))?;
+                if uid < 1000 {
+                    bail!("line {}: UIDs below 1000 are for system users", number + 1);
+                }
+                provision.uid = Some(uid);
+            }
+            "groups" => {
+                provision.groups = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|group| !group.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            "flake" if !value.is_empty() => provision.flake = Some(value.to_string()),
+            key => eprintln!("Ignoring unknown key {} on line {}", key, number + 1),
+        }
+    }
+    if provision.uid.is_some() && provision.user.is_none() {
+        bail!("uid is set, but user isn't");
+    }
+    Ok(provision)
+}
+
+/// The `.nixos-wsl` directories in the home directories of Windows users, on any drive
+fn find_sources(automount_root: &Path) -> Vec<PathBuf> {
+    let mut sources = Vec::new();
+    for drive in read_dir(automount_root).into_iter().flatten().flatten() {
+        let users = drive.path().join("Users");
+        for home in read_dir(users).into_iter().flatten().flatten() {
+            let source = home.path().join(".nixos-wsl");
+            if source.is_dir() {
+                sources.push(source);
+            }
+        }
+    }
+    sources
+}
+
+/// If several Windows users left one, the one changed last is most likely meant for this import
+fn newest(sources: Vec<PathBuf>) -> Option<PathBuf> {
+    sources
+        .into_iter()
+        .max_by_key(|source| source.metadata().and_then(|m| m.modified()).ok())
+}
+
+/// Copies files and directories. Windows drives show every file as executable, so the modes are reset.
+fn copy_tree(from: &Path, to: &Path) -> anyhow::Result<()> {
+    create_dir_all(to).with_context(|| format!("When creating {}", to.display()))?;
+    let entries = read_dir(from).with_context(|| format!("When reading {}", from.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("When reading {}", from.display()))?;
+        let target = to.join(entry.file_name());
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("When checking {}", entry.path().display()))?;
+        if file_type.is_dir() {
+            copy_tree(&entry.path(), &target)?;
+        } else if file_type.is_file() {
+            copy(entry.path(), &target)
+                .with_context(|| format!("When copying {}", entry.path().display()))?;
+            set_permissions(&target, Permissions::from_mode(0o644))
+                .with_context(|| format!("When setting the mode of {}", target.display()))?;
+        } else {
+            eprintln!("Skipping {}, it is not a file", entry.path().display());
+        }
+    }
+    Ok(())
+}
+
+/// Replaces `config_dir` with a copy of `template`. The original is kept next to it, with `.orig` appended.
+fn seed_config(template: &Path, config_dir: &Path) -> anyhow::Result<()> {
+    let mut backup = config_dir.as_os_str().to_owned();
+    backup.push(".orig");
+    let backup = PathBuf::from(backup);
+    if backup.exists() {
+        // Left by an attempt that didn't finish, so the directory only has a partial copy
+        match remove_dir_all(config_dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("When removing {}", config_dir.display()))
+            }
+            _ => {}
+        }
+    } else if config_dir.exists() {
+        rename(config_dir, &backup)
+            .with_context(|| format!("When moving {} aside", config_dir.display()))?;
+    }
+    copy_tree(template, config_dir)
+}
+
+/// Points `wsl.defaultUser` in a configuration.nix at `user`, and pins its UID if one is given.
+/// None if there is no such line.
+fn set_default_user(config: &str, user: &str, uid: Option<u32>) -> Option<String> {
+    let uid_line = uid.map(|uid| format!("users.users.{}.uid = {};", user, uid));
+    let has_uid = |line: &str| Some(line.trim()) == uid_line.as_deref();
+    let keep_uid = config.lines().any(has_uid);
+    let mut found = false;
+    let mut lines = Vec::new();
+    for line in config.lines() {
+        let rest = line.trim_start();
+        if !found && rest.starts_with("wsl.defaultUser =") {
+            found = true;
+            let indent = &line[..line.len() - rest.len()];
+            lines.push(format!("{}wsl.defaultUser = \"{}\";", indent, user));
+            if let (Some(uid_line), false) = (&uid_line, keep_uid) {
+                lines.push(format!("{}{}", indent, uid_line));
+            }
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+    if !found {
+        return None;
+    }
+    lines.push(String::new());
+    Some(lines.join("\n"))
+}
+
+/// Creates the user unless it exists already, and returns its UID
+fn create_user(useradd: &Path, provision: &Provision, user: &str) -> anyhow::Result<u32> {
+    if let Some(existing) = User::from_name(user).context("When looking up the user")? {
+        println!("User {} exists already", user);
+        return Ok(existing.uid.as_raw());
+    }
+
+    let mut command = Command::new(useradd);
+    command.arg("--create-home");
+    if !provision.groups.is_empty() {
+        command.arg("--groups").arg(provision.groups.join(","));
+    }
+    if let Some(uid) = provision.uid {
+        command.arg("--uid").arg(uid.to_string());
+    }
+    let output = command
+        .arg(user)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("When running {}", useradd.display()))?;
+    if !output.status.success() {
+        bail!(
+            "useradd failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    println!("Created user {}", user);
+
+    let created = User::from_name(user)
+        .context("When looking up the user")?
+        .with_context(|| format!("useradd didn't create {}", user))?;
+    Ok(created.uid.as_raw())
+}
+
+/// Renders the pending work as shell variables, for the service to source
+fn render_pending(variables: &[(&str, String)]) -> String {
+    variables
+        .iter()
+        .map(|(name, value)| format!("{}={}\n", name, shell_quote(value)))
+        .collect()
+}
+
+fn write_creating_parent(path: &Path, contents: &str) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).with_context(|| format!("When creating {}", parent.display()))?;
+    }
+    write(path, contents).with_context(|| format!("When writing {}", path.display()))
+}
+
+/// Does everything not done yet. Steps are skipped if they are done already, so running this again after a
+/// failure picks up where it stopped.
+fn provision(args: &Args, source: &Path) -> anyhow::Result<Vec<(&'static str, String)>> {
+    let provision = match read_to_string(source.join("provision.conf")) {
+        Ok(contents) => parse_provision(&contents).context("When parsing provision.conf")?,
+        Err(e) if e.kind() == ErrorKind::NotFound => Provision::default(),
+        Err(e) => return Err(e).context("When reading provision.conf"),
+    };
+    let mut pending = Vec::new();
+
+    let template = source.join("nixos");
+    let seeded = template.is_dir();
+    if seeded {
+        seed_config(&template, &args.config_dir)
+            .with_context(|| format!("When copying {}", template.display()))?;
+        println!(
+            "Copied {} to {}",
+            template.display(),
+            args.config_dir.display()
+        );
+    }
+
+    if let Some(user) = &provision.user {
+        let uid = create_user(&args.useradd, &provision, user)?;
+        pending.push(("DEFAULT_UID", uid.to_string()));
+
+        // A template or flake is expected to set the default user itself
+        let configuration = args.config_dir.join("configuration.nix");
+        if !seeded && provision.flake.is_none() {
+            let contents = read_to_string(&configuration)
+                .with_context(|| format!("When reading {}", configuration.display()))?;
+            match set_default_user(&contents, user, provision.uid) {
+                Some(changed) if changed != contents => {
+                    write(&configuration, changed)
+                        .with_context(|| format!("When writing {}", configuration.display()))?;
+                    pending.push(("REBUILD", "1".to_string()));
+                }
+                Some(_) => {}
+                None => eprintln!(
+                    "{} doesn't set wsl.defaultUser, not changing it",
+                    configuration.display()
+                ),
+            }
+        }
+    }
+
+    if seeded {
+        pending.push(("REBUILD", "1".to_string()));
+    }
+    if let Some(flake) = provision.flake {
+        pending.push(("FLAKE", flake));
+    }
+    if !pending.is_empty() {
+        // The service doesn't get it from WSL, and needs it to find the registry key of the distro
+        if let Ok(distro) = env::var("WSL_DISTRO_NAME") {
+            pending.push(("DISTRO", distro));
+        }
+    }
+    Ok(pending)
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    if args.marker.exists() {
+        println!("Already provisioned");
+        return Ok(());
+    }
+
+    let source = match &args.source {
+        Some(source) => Some(source.clone()),
+        None => newest(find_sources(&args.automount_root)),
+    };
+    match &source {
+        Some(source) => {
+            println!("Provisioning from {}", source.display());
+            let pending = provision(&args, source)?;
+            if !pending.is_empty() {
+                write_creating_parent(&args.pending, &render_pending(&pending))?;
+            }
+        }
+        None => println!("Nothing to provision"),
+    }
+
+    let done = source.map_or(String::new(), |source| format!("{}\n", source.display()));
+    write_creating_parent(&args.marker, &done)
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::fs::create_dir;
+
+    #[test]
+    fn parses_provision_conf() {
+        let provision = parse_provision(
+            "# Made by the installer\nuser = alice\nuid=1001\ngroups=wheel, docker\nflake=github:alice/dots#wsl\n",
+        )
+        .unwrap();
+        assert_eq!(
+            provision,
+            Provision {
+                user: Some("alice".to_string()),
+                uid: Some(1001),
+                groups: vec!["wheel".to_string(), "docker".to_string()],
+                flake: Some("github:alice/dots#wsl".to_string()),
+            }
+        );
+        assert_eq!(parse_provision("").unwrap(), Provision::default());
+        assert!(parse_provision("user=Alice\n").is_err());
+        assert!(parse_provision("user=alice\nuid=0\n").is_err());
+        assert!(parse_provision("uid=1001\n").is_err());
+        assert!(parse_provision("alice\n").is_err());
+    }
+
+    #[test]
+    fn sets_default_user() {
+        let config = "{\n  wsl.enable = true;\n  wsl.defaultUser = \"nixos\";\n}\n";
+        assert_eq!(
+            set_default_user(config, "alice", None).unwrap(),
+            "{\n  wsl.enable = true;\n  wsl.defaultUser = \"alice\";\n}\n"
+        );
+        let pinned = set_default_user(config, "alice", Some(1001)).unwrap();
+        assert_eq!(
+            pinned,
+            "{\n  wsl.enable = true;\n  wsl.defaultUser = \"alice\";\n  users.users.alice.uid = 1001;\n}\n"
+        );
+        // Running again doesn't define the UID twice
+        assert_eq!(
+            set_default_user(&pinned, "alice", Some(1001)).unwrap(),
+            pinned
+        );
+        assert_eq!(set_default_user("{ }\n", "alice", None), None);
+    }
+
+    #[test]
+    fn quotes_pending_work() {
+        assert_eq!(
+            render_pending(&[
+                ("DEFAULT_UID", "1000".to_string()),
+                ("FLAKE", "path:/mnt/c/it's here".to_string())
+            ]),
+            "DEFAULT_UID='1000'\nFLAKE='path:/mnt/c/it'\"'\"'s here'\n"
+        );
+    }
+
+    #[test]
+    fn finds_sources() {
+        let dir = scratch_dir("first-boot-sources");
+        create_dir_all(dir.join("c/Users/alice/.nixos-wsl")).unwrap();
+        create_dir_all(dir.join("c/Users/bob")).unwrap();
+        create_dir_all(dir.join("wslg")).unwrap();
+        assert_eq!(
+            find_sources(&dir),
+            vec![dir.join("c/Users/alice/.nixos-wsl")]
+        );
+        assert_eq!(find_sources(&dir.join("missing")), Vec::<PathBuf>::new());
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn seeds_config_once() {
+        let dir = scratch_dir("first-boot-seed");
+        let template = dir.join("template");
+        create_dir_all(template.join("modules")).unwrap();
+        write(template.join("flake.nix"), "{ }").unwrap();
+        write(template.join("modules/wsl.nix"), "{ }").unwrap();
+        let config_dir = dir.join("nixos");
+        create_dir(&config_dir).unwrap();
+        write(config_dir.join("configuration.nix"), "old").unwrap();
+
+        seed_config(&template, &config_dir).unwrap();
+        // Running again after a failure keeps the original backup
+        seed_config(&template, &config_dir).unwrap();
+        assert_eq!(
+            read_to_string(dir.join("nixos.orig/configuration.nix")).unwrap(),
+            "old"
+        );
+        assert!(!config_dir.join("configuration.nix").exists());
+        assert!(config_dir.join("modules/wsl.nix").is_file());
+        let mode = config_dir
+            .join("flake.nix")
+            .metadata()
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o644);
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+use std::cmp::Reverse;
+use std::fs::{canonicalize, read_dir, read_link, remove_file};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+pub const PROFILES_DIR: &str = "/nix/var/nix/profiles";
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// Set by nixos-wsl-stage to boot a generation once without touching the system profile.
+/// The symlink doubles as a GC root, so the staged system can't be collected before the next boot.
+pub const NEXT_BOOT_POINTER: &str = "/nix/var/nix/gcroots/nixos-wsl/next-boot";
+
+/// A NixOS system, i.e. the result of `system.build.toplevel`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generation {
+    pub path: PathBuf,
+}
+
+impl Generation {
+    pub fn new(path: impl Into<PathBuf>) -> Generation {
+        Generation { path: path.into() }
+    }
+
+    pub fn activate(&self) -> PathBuf {
+        self.path.join("activate")
+    }
+
+    pub fn systemd(&self) -> PathBuf {
+        self.path.join("systemd/lib/systemd/systemd")
+    }
+
+    pub fn is_bootable(&self) -> bool {
+        self.activate().exists() && self.systemd().exists()
+    }
+}
+
+fn read_next_boot(pointer: &Path) -> Option<PathBuf> {
+    match read_link(pointer) {
+        Ok(target) => Some(target),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            log::warn!("Could not read {}: {}", pointer.display(), e);
+            None
+        }
+    }
+}
+
+fn staged_generation(target: PathBuf) -> Option<Generation> {
+    let generation = Generation::new(target);
+    if generation.is_bootable() {
+        Some(generation)
+    } else {
+        log::error!(
+            "Staged generation {} is not a NixOS system, ignoring it",
+            generation.path.display()
+        );
+        None
+    }
+}
+
+/// Returns the staged generation, if there is one, and removes the pointer.
+/// Removing it before booting means a broken generation is only tried once.
+pub fn take_next_boot(pointer: &Path) -> Option<Generation> {
+    let target = read_next_boot(pointer)?;
+    if let Err(e) = remove_file(pointer) {
+        log::warn!("Could not remove {}: {}", pointer.display(), e);
+    }
+    staged_generation(target)
+}
+
+/// Like `take_next_boot`, but leaves the pointer in place
+pub fn peek_next_boot(pointer: &Path) -> Option<Generation> {
+    staged_generation(read_next_boot(pointer)?)
+}
+
+/// The generation to boot: the staged one if there is one, otherwise the system profile
+pub fn boot_generation() -> Generation {
+    take_next_boot(Path::new(NEXT_BOOT_POINTER)).unwrap_or_else(|| Generation::new(SYSTEM_PROFILE))
+}
+
+/// The generation the next boot would use, without using up a staged one
+pub fn peek_boot_generation() -> Generation {
+    peek_next_boot(Path::new(NEXT_BOOT_POINTER)).unwrap_or_else(|| Generation::new(SYSTEM_PROFILE))
+}
+
+/// Parses the generation number out of a `system-<N>-link` profile entry
+pub fn generation_number(name: &str) -> Option<u64> {
+    name.strip_prefix("system-")?
+        .strip_suffix("-link")?
+        .parse()
+        .ok()
+}
+
+/// Bootable generations to try when `failed` doesn't activate, newest first.
+/// If `failed` is one of the profile's generations, only older ones are returned,
+/// since anything newer was rolled back from and is even less likely to work.
+pub fn fallback_generations(profiles_dir: &Path, failed: &Generation) -> Vec<Generation> {
+    let entries = match read_dir(profiles_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("Could not list {}: {}", profiles_dir.display(), e);
+            return vec![];
+        }
+    };
+
+    let mut links: Vec<(u64, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let number = generation_number(entry.file_name().to_str()?)?;
+            Some((number, entry.path()))
+        })
+        .collect();
+    links.sort_by_key(|(number, _)| Reverse(*number));
+
+    let failed_target = canonicalize(&failed.path).ok();
+    let same_as_failed =
+        |link: &Path| failed_target.is_some() && canonicalize(link).ok() == failed_target;
+    if let Some(failed_number) = links
+        .iter()
+        .find(|(_, link)| same_as_failed(link))
+        .map(|(number, _)| *number)
+    {
+        links.retain(|(number, _)| *number < failed_number);
+    }
+
+    links
+        .into_iter()
+        .map(|(_, link)| Generation::new(link))
+        .filter(|generation| generation.is_bootable())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::os::unix::fs::symlink;
+
+    fn fake_generation(dir: &Path) -> Generation {
+        let generation = Generation::new(dir.join("system"));
+        create_dir_all(generation.systemd().parent().unwrap()).unwrap();
+        write(generation.activate(), "").unwrap();
+        write(generation.systemd(), "").unwrap();
+        generation
+    }
+
+    fn fake_profile(dir: &Path, numbers: &[u64]) -> PathBuf {
+        let profiles = dir.join("profiles");
+        create_dir_all(&profiles).unwrap();
+        for number in numbers {
+            let generation = fake_generation(&dir.join(number.to_string()));
+            symlink(
+                &generation.path,
+                profiles.join(format!("system-{}-link", number)),
+            )
+            .unwrap();
+        }
+        profiles
+    }
+
+    #[test]
+    fn parses_generation_numbers() {
+        assert_eq!(generation_number("system-42-link"), Some(42));
+        assert_eq!(generation_number("system"), None);
+        assert_eq!(generation_number("system-x-link"), None);
+        assert_eq!(generation_number("home-manager-1-link"), None);
+    }
+
+    #[test]
+    fn falls_back_to_older_generations() {
+        let dir = scratch_dir("fallback");
+        let profiles = fake_profile(&dir, &[1, 2, 10, 11]);
+        symlink("system-10-link", profiles.join("system")).unwrap();
+
+        let fallbacks = fallback_generations(&profiles, &Generation::new(profiles.join("system")));
+        assert_eq!(
+            fallbacks,
+            vec![
+                Generation::new(profiles.join("system-2-link")),
+                Generation::new(profiles.join("system-1-link")),
+            ]
+        );
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn staged_generation_falls_back_to_all() {
+        let dir = scratch_dir("fallback-staged");
+        let profiles = fake_profile(&dir, &[1, 2]);
+        let staged = fake_generation(&dir.join("staged"));
+        // Not a system, so it can't be booted
+        create_dir_all(dir.join("3")).unwrap();
+        symlink(dir.join("3"), profiles.join("system-3-link")).unwrap();
+
+        assert_eq!(
+            fallback_generations(&profiles, &staged),
+            vec![
+                Generation::new(profiles.join("system-2-link")),
+                Generation::new(profiles.join("system-1-link")),
+            ]
+        );
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn no_pointer_means_no_generation() {
+        let dir = scratch_dir("no-pointer");
+        assert_eq!(take_next_boot(&dir.join("next-boot")), None);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn staged_generation_is_taken_once() {
+        let dir = scratch_dir("staged");
+        let generation = fake_generation(&dir);
+        let pointer = dir.join("next-boot");
+        symlink(&generation.path, &pointer).unwrap();
+
+        assert_eq!(take_next_boot(&pointer), Some(generation));
+        assert!(!pointer.is_symlink());
+        assert_eq!(take_next_boot(&pointer), None);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn peeking_leaves_staged_generation() {
+        let dir = scratch_dir("staged-peek");
+        let generation = fake_generation(&dir);
+        let pointer = dir.join("next-boot");
+        symlink(&generation.path, &pointer).unwrap();
+
+        assert_eq!(peek_next_boot(&pointer), Some(generation.clone()));
+        assert_eq!(take_next_boot(&pointer), Some(generation));
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn non_system_is_ignored() {
+        let dir = scratch_dir("not-a-system");
+        let pointer = dir.join("next-boot");
+        symlink(&dir, &pointer).unwrap();
+
+        assert_eq!(take_next_boot(&pointer), None);
+        assert!(!pointer.is_symlink());
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::{anyhow, Context};
+use nix::mount::MsFlags;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+
+use crate::config::HugepagesConfig;
+use crate::effects::{self, create_dir_all, write};
+use crate::mounts::{Done, Mount, MountOp, MountPlan};
+
+pub fn nr_hugepages_path(page_size_kb: u64) -> PathBuf {
+    PathBuf::from(format!(
+        "/sys/kernel/mm/hugepages/hugepages-{}kB/nr_hugepages",
+        page_size_kb
+    ))
+}
+
+/// Reads the default hugepage size from the `Hugepagesize:` line of /proc/meminfo
+fn default_page_size_kb(meminfo: &str) -> Option<u64> {
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Hugepagesize:"))
+        .and_then(|rest| rest.trim().strip_suffix("kB"))
+        .and_then(|size| size.trim().parse().ok())
+}
+
+pub fn setup_hugepages(config: &HugepagesConfig) -> anyhow::Result<()> {
+    if config.pages == 0 {
+        log::trace!("No hugepages requested, skipping...");
+        return Ok(());
+    }
+
+    let page_size_kb = match config.page_size_kb {
+        Some(size) => size,
+        None => default_page_size_kb(
+            &read_to_string("/proc/meminfo").context("When reading /proc/meminfo")?,
+        )
+        .ok_or(anyhow!("the kernel does not support hugepages"))?,
+    };
+
+    log::trace!(
+        "Reserving {} hugepages of {}kB...",
+        config.pages,
+        page_size_kb
+    );
+    let nr_hugepages = nr_hugepages_path(page_size_kb);
+    write(&nr_hugepages, &config.pages.to_string())
+        .with_context(|| format!("When writing {}", nr_hugepages.display()))?;
+
+    // The kernel reserves as many pages as it can find contiguous memory for, which might be fewer
+    if !effects::is_dry_run() {
+        let reserved: u64 = read_to_string(&nr_hugepages)
+            .with_context(|| format!("When reading {}", nr_hugepages.display()))?
+            .trim()
+            .parse()
+            .context("When parsing the number of reserved hugepages")?;
+        if reserved < config.pages {
+            log::warn!(
+                "Only {} of {} requested hugepages could be reserved",
+                reserved,
+                config.pages
+            );
+        }
+    }
+
+    create_dir_all(&config.mount_point)
+        .with_context(|| format!("When creating {}", config.mount_point.display()))?;
+    MountPlan::new(vec![MountOp {
+        name: "hugetlbfs",
+        after: &[],
+        mount: Mount {
+            source: Some("hugetlbfs".into()),
+            target: config.mount_point.clone(),
+            fstype: Some("hugetlbfs"),
+            flags: MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            data: Some(format!("pagesize={}K", page_size_kb)),
+        },
+        done: Done::IfMounted,
+    }])?
+    .run()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_default_page_size() {
+        let meminfo =
+            "MemTotal:       16318480 kB\nHugePages_Total:       0\nHugepagesize:       2048 kB\n";
+        assert_eq!(default_page_size_kb(meminfo), Some(2048));
+    }
+
+    #[test]
+    fn no_hugepage_support() {
+        assert_eq!(default_page_size_kb("MemTotal:       16318480 kB\n"), None);
+    }
+
+    #[test]
+    fn sysfs_path_uses_page_size() {
+        assert_eq!(
+            nr_hugepages_path(1048576),
+            PathBuf::from("/sys/kernel/mm/hugepages/hugepages-1048576kB/nr_hugepages")
+        );
+    }
+
+    #[test]
+    fn zero_pages_is_a_noop() {
+        assert!(setup_hugepages(&HugepagesConfig::default()).is_ok());
+    }
+}
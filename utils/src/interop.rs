@@ -0,0 +1,142 @@
+use anyhow::{bail, Context};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::env;
+use std::fs::{read_dir, symlink_metadata};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Kept up to date by wsl.interop.stableSocket
+pub const STABLE_SOCKET: &str = "/run/nixos-wsl/interop";
+/// WSL creates a `<pid>_interop` socket here for every session
+pub const SOCKET_DIR: &str = "/run/WSL";
+
+/// Interop sockets in `dir` that accept connections, newest first
+pub fn live_sockets(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut sockets: Vec<(SystemTime, PathBuf)> = read_dir(dir)
+        .with_context(|| format!("When listing {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .map_or(false, |name| name.ends_with("_interop"))
+        })
+        .map(|entry| entry.path())
+        // The socket files of sessions that are gone stay around, but refuse connections
+        .filter(|path| UnixStream::connect(path).is_ok())
+        .map(|path| {
+            let modified = symlink_metadata(&path)
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            (modified, path)
+        })
+        .collect();
+    // The PIDs in the names say nothing about age, they wrap around
+    sockets.sort_by(|a, b| b.cmp(a));
+    Ok(sockets.into_iter().map(|(_, path)| path).collect())
+}
+
+/// `stable` if it accepts connections, otherwise the newest live session socket in `dir`
+pub fn interop_socket(stable: &Path, dir: &Path) -> Option<PathBuf> {
+    if UnixStream::connect(stable).is_ok() {
+        return Some(stable.to_path_buf());
+    }
+    live_sockets(dir).ok()?.into_iter().next()
+}
+
+/// systemd services don't get WSL_INTEROP, so `command` gets the socket of a session that is still open
+pub fn use_live_socket(command: &mut Command) {
+    if env::var_os("WSL_INTEROP").is_some() {
+        return;
+    }
+    if let Some(socket) = interop_socket(Path::new(STABLE_SOCKET), Path::new(SOCKET_DIR)) {
+        command.env("WSL_INTEROP", socket);
+    }
+}
+
+/// Runs `command` with its output captured, and kills it if it hasn't exited after `timeout`.
+/// Interop hangs now and then, e.g. while Windows resumes from sleep.
+pub fn run_with_timeout(command: &mut Command, timeout: Duration) -> anyhow::Result<Output> {
+    let program = PathBuf::from(command.get_program());
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("When starting {}", program.display()))?;
+    let pid = Pid::from_raw(child.id() as i32);
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(child.wait_with_output()));
+    match receiver.recv_timeout(timeout) {
+        Ok(output) => output.with_context(|| format!("When waiting for {}", program.display())),
+        Err(_) => {
+            let _ = kill(pid, Signal::SIGKILL);
+            bail!(
+                "{} didn't answer within {} seconds",
+                program.display(),
+                timeout.as_secs()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::fs::remove_dir_all;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn prefers_stable_then_newest_socket() {
+        let dir = scratch_dir("interop-fallback");
+        let stable = dir.join("stable");
+        let _old = UnixListener::bind(dir.join("9_interop")).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let _new = UnixListener::bind(dir.join("12_interop")).unwrap();
+        assert_eq!(interop_socket(&stable, &dir), Some(dir.join("12_interop")));
+
+        let _stable = UnixListener::bind(&stable).unwrap();
+        assert_eq!(interop_socket(&stable, &dir), Some(stable));
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn finds_live_sockets() {
+        let dir = scratch_dir("interop");
+        let _older = UnixListener::bind(dir.join("12_interop")).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let _newer = UnixListener::bind(dir.join("9_interop")).unwrap();
+        // Bound and closed again, like the socket of a session that has ended
+        drop(UnixListener::bind(dir.join("7_interop")).unwrap());
+        let _other = UnixListener::bind(dir.join("unrelated")).unwrap();
+
+        assert_eq!(
+            live_sockets(&dir).unwrap(),
+            vec![dir.join("9_interop"), dir.join("12_interop")]
+        );
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn kills_commands_that_hang() {
+        let output = run_with_timeout(
+            Command::new("/bin/sh").args(["-c", "echo hi"]),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(output.stdout, b"hi\n");
+
+        let err = run_with_timeout(
+            Command::new("/bin/sh").args(["-c", "sleep 10"]),
+            Duration::from_millis(50),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("didn't answer"));
+    }
+}
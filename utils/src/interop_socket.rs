@@ -0,0 +1,116 @@
+#[cfg(test)]
+mod test_util;
+
+use anyhow::Context;
+use clap::Parser;
+use std::fs::{create_dir_all, read_link, remove_file, rename};
+use std::io::ErrorKind;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+use nixos_wsl_utils::interop::{live_sockets, SOCKET_DIR, STABLE_SOCKET};
+
+/// Points a stable path at a working WSL interop socket.
+/// WSL creates a new <pid>_interop socket for every session, so WSL_INTEROP goes stale in
+/// long-running tmux sessions or systemd services once the session that started them is gone.
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-interop-socket", version = build_info::VERSION)]
+struct Args {
+    #[arg(long, default_value = SOCKET_DIR)]
+    dir: PathBuf,
+
+    /// Kept out of --dir, so updating it doesn't trigger the path unit watching --dir again
+    #[arg(long, default_value = STABLE_SOCKET)]
+    link: PathBuf,
+
+    /// Only print the live socket, don't touch the symlink
+    #[arg(long)]
+    print: bool,
+}
+
+/// Atomically points `link` at `target`, or removes it if there is no target.
+/// Leaves a link that is already right alone.
+fn update_link(link: &Path, target: Option<&Path>) -> anyhow::Result<()> {
+    let Some(target) = target else {
+        return match remove_file(link) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("When removing {}", link.display()))
+            }
+            _ => Ok(()),
+        };
+    };
+
+    if read_link(link).map_or(false, |current| current == target) {
+        return Ok(());
+    }
+    if let Some(parent) = link.parent() {
+        create_dir_all(parent).with_context(|| format!("When creating {}", parent.display()))?;
+    }
+    let temp = link.with_file_name(format!(
+        ".{}.tmp",
+        link.file_name().and_then(|n| n.to_str()).unwrap_or("link")
+    ));
+    let _ = remove_file(&temp);
+    symlink(target, &temp).with_context(|| format!("When creating {}", temp.display()))?;
+    rename(&temp, link).with_context(|| format!("When replacing {}", link.display()))
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    let sockets = live_sockets(&args.dir)?;
+    let live = sockets.first();
+
+    if args.print {
+        match live {
+            Some(socket) => println!("{}", socket.display()),
+            None => anyhow::bail!("No working interop socket in {}", args.dir.display()),
+        }
+        return Ok(());
+    }
+
+    let link = &args.link;
+    update_link(link, live.map(PathBuf::as_path))?;
+    match live {
+        Some(socket) => println!("{} -> {}", link.display(), socket.display()),
+        None => eprintln!("No working interop socket, removed {}", link.display()),
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::fs::{remove_dir_all, symlink_metadata};
+
+    #[test]
+    fn updates_link() {
+        let dir = scratch_dir("interop-link");
+        let link = dir.join("stable/interop");
+
+        update_link(&link, Some(&dir.join("1_interop"))).unwrap();
+        update_link(&link, Some(&dir.join("2_interop"))).unwrap();
+        assert_eq!(read_link(&link).unwrap(), dir.join("2_interop"));
+        let modified = symlink_metadata(&link).unwrap().modified().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        update_link(&link, Some(&dir.join("2_interop"))).unwrap();
+        assert_eq!(
+            symlink_metadata(&link).unwrap().modified().unwrap(),
+            modified
+        );
+
+        update_link(&link, None).unwrap();
+        assert!(!link.is_symlink());
+        update_link(&link, None).unwrap();
+        remove_dir_all(dir).unwrap();
+    }
+}
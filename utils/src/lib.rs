@@ -0,0 +1,10 @@
+//! Code shared between the utils binaries. Everything else stays in the binary that uses it.
+
+pub mod build_info;
+pub mod generations;
+pub mod interop;
+pub mod mountinfo;
+pub mod quote;
+
+#[cfg(test)]
+mod test_util;
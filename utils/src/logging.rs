@@ -0,0 +1,418 @@
+use anyhow::{anyhow, bail, Context};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::env;
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file, File, OpenOptions};
+use std::io::{stderr, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use nixos_wsl_utils::quote::json_string;
+
+/// Set e.g. to `debug,json,kmsg,stderr`. Also accepted on the kernel command line as `nixos-wsl.log=...`.
+const LOG_ENV: &str = "NIXOS_WSL_LOG";
+const LOG_CMDLINE: &str = "nixos-wsl.log=";
+pub const LOG_DIR: &str = "/var/log/nixos-wsl";
+/// Every boot gets its own shim-<time>.log, and the oldest ones are removed
+const KEEP_SHIM_LOGS: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogSettings {
+    pub level: LevelFilter,
+    /// Only used for the file target. Lower than `level` by default, since nothing reads
+    /// the file until something went wrong, and then the earlier boots matter too.
+    pub file_level: LevelFilter,
+    pub json: bool,
+    pub kmsg: bool,
+    /// Writes to /var/log/nixos-wsl/shim-<time>.log, which is still around (and readable from Windows) after a failed boot
+    pub file: bool,
+    pub stderr: bool,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            level: LevelFilter::Trace,
+            file_level: LevelFilter::Info,
+            json: false,
+            kmsg: true,
+            file: true,
+            stderr: false,
+        }
+    }
+}
+
+impl LogSettings {
+    /// Anything but a boot, e.g. `info` or a dry run, only logs to stderr.
+    /// The shim log would otherwise rotate away the logs of real boots, and only root can open /dev/kmsg.
+    pub fn for_run(self, boot: bool) -> LogSettings {
+        if boot {
+            return self;
+        }
+        LogSettings {
+            level: self.file_level,
+            kmsg: false,
+            file: false,
+            stderr: true,
+            ..self
+        }
+    }
+}
+
+/// Parses a comma separated list of a level, a format (`text` or `json`) and targets (`kmsg`, `file`, `stderr`).
+/// Listing any target replaces the default ones, and a level applies to all targets.
+pub fn parse_settings(value: &str) -> anyhow::Result<LogSettings> {
+    let mut settings = LogSettings::default();
+    let mut targets = None;
+    for word in value.split(',').map(str::trim).filter(|w| !w.is_empty()) {
+        match word {
+            "text" => settings.json = false,
+            "json" => settings.json = true,
+            "kmsg" | "file" | "stderr" => targets.get_or_insert_with(Vec::new).push(word),
+            _ => {
+                settings.level = word
+                    .parse()
+                    .map_err(|_| anyhow!("invalid log setting: {}", word))?;
+                settings.file_level = settings.level;
+            }
+        }
+    }
+    if let Some(targets) = targets {
+        settings.kmsg = targets.contains(&"kmsg");
+        settings.file = targets.contains(&"file");
+        settings.stderr = targets.contains(&"stderr");
+    }
+    Ok(settings)
+}
+
+fn settings_from_cmdline(cmdline: &str) -> Option<&str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix(LOG_CMDLINE))
+        .next_back()
+}
+
+/// syslog priority, as used by kmsg
+fn priority(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 5,
+        Level::Debug => 6,
+        Level::Trace => 7,
+    }
+}
+
+struct Entry<'a> {
+    time: f64,
+    level: Level,
+    target: &'a str,
+    pid: u32,
+    message: String,
+}
+
+impl Entry<'_> {
+    fn format(&self, json: bool) -> String {
+        if json {
+            format!(
+                "{{\"time\":{:.3},\"level\":\"{}\",\"target\":{},\"pid\":{},\"message\":{}}}",
+                self.time,
+                self.level.as_str().to_lowercase(),
+                json_string(self.target),
+                self.pid,
+                json_string(&self.message)
+            )
+        } else {
+            format!(
+                "{:.3} {} {}[{}]: {}",
+                self.time, self.level, self.target, self.pid, self.message
+            )
+        }
+    }
+
+    /// The kernel keeps the priority prefix and adds its own timestamp, so the text format matches kernlog's
+    fn format_kmsg(&self, json: bool) -> String {
+        if json {
+            format!("<{}>{}", priority(self.level), self.format(true))
+        } else {
+            format!(
+                "<{}>{}[{}]: {}",
+                priority(self.level),
+                self.target,
+                self.pid,
+                self.message
+            )
+        }
+    }
+}
+
+pub enum Output {
+    Kmsg(File),
+    File(File),
+    Stderr,
+}
+
+struct Logger {
+    settings: LogSettings,
+    outputs: Mutex<Vec<Output>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.settings.level
+            || (self.settings.file && metadata.level() <= self.settings.file_level)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let entry = Entry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0),
+            level: record.level(),
+            target: record.target(),
+            pid: std::process::id(),
+            message: record.args().to_string(),
+        };
+
+        if let Ok(mut outputs) = self.outputs.lock() {
+            for output in outputs.iter_mut() {
+                let level = match output {
+                    Output::File(_) => self.settings.file_level,
+                    Output::Kmsg(_) | Output::Stderr => self.settings.level,
+                };
+                if entry.level > level {
+                    continue;
+                }
+                // Nowhere left to report write errors to, so they are dropped
+                let _ = match output {
+                    // Each write is one kmsg record, so the line has to go out in one piece
+                    Output::Kmsg(kmsg) => kmsg.write_all(
+                        format!("{}\n", entry.format_kmsg(self.settings.json)).as_bytes(),
+                    ),
+                    Output::File(file) => {
+                        file.write_all(format!("{}\n", entry.format(self.settings.json)).as_bytes())
+                    }
+                    Output::Stderr => writeln!(stderr(), "{}", entry.format(self.settings.json)),
+                };
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Whether `path` is one of the `<prefix>-<time>.log` files made by `open_log`
+fn is_log(path: &Path, prefix: &str) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix(prefix))
+        .map_or(false, |rest| {
+            rest.starts_with('-') && rest.ends_with(".log")
+        })
+}
+
+/// Removes the oldest `<prefix>-<time>.log` files in `dir`, so at most `keep` are left
+fn rotate_logs(dir: &Path, prefix: &str, keep: usize) -> anyhow::Result<()> {
+    let mut logs: Vec<(SystemTime, PathBuf)> = read_dir(dir)
+        .with_context(|| format!("When listing {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| is_log(path, prefix))
+        .map(|path| {
+            let modified = path
+                .metadata()
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            (modified, path)
+        })
+        .collect();
+    // Newest first, the names break ties between logs from the same second
+    logs.sort_by(|a, b| b.cmp(a));
+    for (_, path) in logs.iter().skip(keep) {
+        remove_file(path).with_context(|| format!("When removing {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Creates a new log file in `dir`, named after `prefix` and `now` (seconds since the epoch),
+/// and removes old ones so at most `keep` are left including the new one
+pub fn open_log(dir: &Path, prefix: &str, keep: usize, now: u64) -> anyhow::Result<File> {
+    create_dir_all(dir).with_context(|| format!("When creating {}", dir.display()))?;
+    if let Err(e) = rotate_logs(dir, prefix, keep.saturating_sub(1)) {
+        log::warn!("Could not remove old {} logs: {:?}", prefix, e);
+    }
+
+    // A fallback can activate several generations within the same second
+    let mut attempt = 0;
+    loop {
+        let name = if attempt == 0 {
+            format!("{}-{}.log", prefix, now)
+        } else {
+            format!("{}-{}-{}.log", prefix, now, attempt)
+        };
+        let path = dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok(file),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(e).with_context(|| format!("When creating {}", path.display())),
+        }
+    }
+}
+
+/// Opens every target in `settings` that can be opened, with the shim log in `log_dir`
+pub fn open_outputs(settings: &LogSettings, log_dir: &Path) -> (Vec<Output>, Vec<anyhow::Error>) {
+    let mut outputs = vec![];
+    let mut problems = vec![];
+    if settings.kmsg {
+        match OpenOptions::new().write(true).open("/dev/kmsg") {
+            Ok(kmsg) => outputs.push(Output::Kmsg(kmsg)),
+            Err(e) => problems.push(anyhow::Error::from(e).context("When opening /dev/kmsg")),
+        }
+    }
+    if settings.file {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        match open_log(log_dir, "shim", KEEP_SHIM_LOGS, now) {
+            Ok(file) => outputs.push(Output::File(file)),
+            Err(e) => problems.push(e),
+        }
+    }
+    if settings.stderr {
+        outputs.push(Output::Stderr);
+    }
+    (outputs, problems)
+}
+
+/// Sets up logging to every target that can be opened, or only to stderr unless `boot`.
+/// Errors are returned after the logger is installed, so the remaining targets still work.
+pub fn init(boot: bool) -> anyhow::Result<()> {
+    let mut problems = vec![];
+
+    let configured = env::var(LOG_ENV).ok().or_else(|| {
+        read_to_string("/proc/cmdline")
+            .ok()
+            .and_then(|cmdline| settings_from_cmdline(&cmdline).map(String::from))
+    });
+    let settings = match configured.as_deref().map(parse_settings) {
+        Some(Ok(settings)) => settings,
+        Some(Err(e)) => {
+            problems.push(e.context(format!("When parsing {}", LOG_ENV)));
+            LogSettings::default()
+        }
+        None => LogSettings::default(),
+    };
+    let settings = settings.for_run(boot);
+
+    let (outputs, open_problems) = open_outputs(&settings, Path::new(LOG_DIR));
+    problems.extend(open_problems);
+
+    let level = settings.level;
+    log::set_boxed_logger(Box::new(Logger {
+        settings,
+        outputs: Mutex::new(outputs),
+    }))
+    .context("When installing the logger")?;
+    log::set_max_level(level);
+
+    if !problems.is_empty() {
+        let problems: Vec<String> = problems.iter().map(|e| format!("{:#}", e)).collect();
+        bail!("{}", problems.join("; "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn empty_settings_are_default() {
+        assert_eq!(parse_settings("").unwrap(), LogSettings::default());
+    }
+
+    #[test]
+    fn parses_settings() {
+        assert_eq!(
+            parse_settings("debug,json,kmsg,stderr").unwrap(),
+            LogSettings {
+                level: LevelFilter::Debug,
+                file_level: LevelFilter::Debug,
+                json: true,
+                kmsg: true,
+                file: false,
+                stderr: true,
+            }
+        );
+        assert!(parse_settings("loud").is_err());
+    }
+
+    #[test]
+    fn reads_settings_from_cmdline() {
+        assert_eq!(
+            settings_from_cmdline("quiet nixos-wsl.log=info,json\n"),
+            Some("info,json")
+        );
+        assert_eq!(settings_from_cmdline("quiet"), None);
+    }
+
+    #[test]
+    fn rotates_logs() {
+        let dir = scratch_dir("rotated-logs");
+        for now in [1, 2, 3] {
+            open_log(&dir, "activation", 3, now).unwrap();
+        }
+        // Same second as the last one
+        open_log(&dir, "activation", 3, 3).unwrap();
+        open_log(&dir, "shim", 3, 1).unwrap();
+
+        let mut names: Vec<String> = read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names.len(), 4);
+        assert!(names.contains(&"activation-3.log".to_string()));
+        assert!(names.contains(&"activation-3-1.log".to_string()));
+        assert!(names.contains(&"shim-1.log".to_string()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_level_defaults_lower() {
+        let settings = LogSettings::default();
+        assert!(settings.file_level < settings.level);
+        let settings = parse_settings("debug").unwrap();
+        assert_eq!(settings.file_level, LevelFilter::Debug);
+    }
+
+    #[test]
+    fn formats_entries() {
+        let entry = Entry {
+            time: 12.5,
+            level: Level::Warn,
+            target: "systemd_shim",
+            pid: 1,
+            message: "Step \"dev-shm\" failed".to_string(),
+        };
+        assert_eq!(
+            entry.format(true),
+            "{\"time\":12.500,\"level\":\"warn\",\"target\":\"systemd_shim\",\"pid\":1,\"message\":\"Step \\\"dev-shm\\\" failed\"}"
+        );
+        assert_eq!(
+            entry.format(false),
+            "12.500 WARN systemd_shim[1]: Step \"dev-shm\" failed"
+        );
+        assert_eq!(
+            entry.format_kmsg(false),
+            "<4>systemd_shim[1]: Step \"dev-shm\" failed"
+        );
+        assert!(entry.format_kmsg(true).starts_with("<4>{\"time\""));
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::Context;
+use nix::mount::MsFlags;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// One line of /proc/self/mountinfo, see proc(5)
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountEntry {
+    pub mount_point: PathBuf,
+    /// Per-mount options, e.g. `ro` or `nosuid`
+    pub options: Vec<String>,
+    /// Propagation tags, e.g. `shared:1` or `master:2`
+    pub optional: Vec<String>,
+    pub fstype: String,
+    pub source: String,
+}
+
+impl MountEntry {
+    pub fn is_read_only(&self) -> bool {
+        self.options.iter().any(|option| option == "ro")
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.optional.iter().any(|tag| tag.starts_with("shared:"))
+    }
+
+    /// The per-mount options as mount(2) flags. A remount drops every flag it isn't passed, so it needs these.
+    pub fn flags(&self) -> MsFlags {
+        self.options
+            .iter()
+            .filter_map(|option| match option.as_str() {
+                "ro" => Some(MsFlags::MS_RDONLY),
+                "nosuid" => Some(MsFlags::MS_NOSUID),
+                "nodev" => Some(MsFlags::MS_NODEV),
+                "noexec" => Some(MsFlags::MS_NOEXEC),
+                "noatime" => Some(MsFlags::MS_NOATIME),
+                "nodiratime" => Some(MsFlags::MS_NODIRATIME),
+                "relatime" => Some(MsFlags::MS_RELATIME),
+                _ => None,
+            })
+            .fold(MsFlags::empty(), |flags, flag| flags | flag)
+    }
+}
+
+/// Decodes the octal escapes (e.g. `\040` for a space) the kernel uses for paths in mountinfo and /proc/mounts
+pub fn unescape_mountinfo_path(path: &str) -> String {
+    let mut result = String::with_capacity(path.len());
+    let mut rest = path;
+    while let Some(pos) = rest.find('\\') {
+        result.push_str(&rest[..pos]);
+        let code = rest
+            .get(pos + 1..pos + 4)
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match code {
+            Some(byte) => {
+                result.push(byte as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                result.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn parse_line(line: &str) -> Option<MountEntry> {
+    let mut fields = line.split(' ');
+    // Mount ID, parent ID, major:minor and the root of the mount
+    let mount_point = fields.nth(4)?;
+    let options = fields.next()?;
+    let optional: Vec<String> = fields
+        .by_ref()
+        .take_while(|field| *field != "-")
+        .map(String::from)
+        .collect();
+    let fstype = fields.next()?;
+    let source = fields.next()?;
+    Some(MountEntry {
+        mount_point: PathBuf::from(unescape_mountinfo_path(mount_point)),
+        options: options.split(',').map(String::from).collect(),
+        optional,
+        fstype: fstype.to_string(),
+        source: unescape_mountinfo_path(source),
+    })
+}
+
+/// The mount table, in the order the kernel lists it, i.e. mounts on top of others come later
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MountInfo {
+    pub entries: Vec<MountEntry>,
+}
+
+impl MountInfo {
+    pub fn parse(mountinfo: &str) -> MountInfo {
+        MountInfo {
+            entries: mountinfo.lines().filter_map(parse_line).collect(),
+        }
+    }
+
+    pub fn read() -> anyhow::Result<MountInfo> {
+        let mountinfo = read_to_string("/proc/self/mountinfo").context("When reading mountinfo")?;
+        Ok(MountInfo::parse(&mountinfo))
+    }
+
+    /// The mount that is visible at `path`, if `path` is a mount point
+    pub fn find(&self, path: &Path) -> Option<&MountEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.mount_point == path)
+    }
+
+    pub fn is_mountpoint(&self, path: &Path) -> bool {
+        self.find(path).is_some()
+    }
+
+    /// Mounts at or below `path`
+    pub fn below<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a MountEntry> {
+        self.entries
+            .iter()
+            .filter(move |entry| entry.mount_point.starts_with(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 8:32 / / rw,relatime shared:1 - ext4 /dev/sdc rw
+31 22 0:26 / /run rw,nosuid,nodev shared:2 master:7 - tmpfs none rw,mode=755
+35 31 0:30 / /run/shm rw,nosuid,nodev - tmpfs none rw
+40 22 0:40 / /mnt/my\\040drive rw - 9p drvfs rw
+41 22 8:32 /nix/store /nix/store rw,relatime shared:1 - ext4 /dev/sdc rw
+42 41 8:32 /nix/store /nix/store ro,relatime shared:1 - ext4 /dev/sdc rw
+";
+
+    fn mountinfo() -> MountInfo {
+        MountInfo::parse(MOUNTINFO)
+    }
+
+    #[test]
+    fn parses_entries() {
+        assert_eq!(
+            mountinfo().entries[1],
+            MountEntry {
+                mount_point: PathBuf::from("/run"),
+                options: vec!["rw", "nosuid", "nodev"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                optional: vec!["shared:2".to_string(), "master:7".to_string()],
+                fstype: "tmpfs".to_string(),
+                source: "none".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn converts_options_to_flags() {
+        let mountinfo = mountinfo();
+        assert_eq!(
+            mountinfo.entries[1].flags(),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV
+        );
+        assert_eq!(
+            mountinfo.entries[5].flags(),
+            MsFlags::MS_RDONLY | MsFlags::MS_RELATIME
+        );
+    }
+
+    #[test]
+    fn finds_mountpoints() {
+        let mountinfo = mountinfo();
+        assert!(mountinfo.is_mountpoint(Path::new("/run/shm")));
+        assert!(mountinfo.is_mountpoint(Path::new("/")));
+        assert!(!mountinfo.is_mountpoint(Path::new("/dev/shm")));
+    }
+
+    #[test]
+    fn finds_escaped_mountpoints() {
+        assert!(mountinfo().is_mountpoint(Path::new("/mnt/my drive")));
+    }
+
+    #[test]
+    fn unescapes_mountinfo_paths() {
+        assert_eq!(unescape_mountinfo_path("/a\\011b\\134c"), "/a\tb\\c");
+        assert_eq!(unescape_mountinfo_path("/trailing\\"), "/trailing\\");
+    }
+
+    #[test]
+    fn top_mount_wins() {
+        let mountinfo = mountinfo();
+        assert!(mountinfo
+            .find(Path::new("/nix/store"))
+            .unwrap()
+            .is_read_only());
+    }
+
+    #[test]
+    fn reads_propagation() {
+        let mountinfo = mountinfo();
+        assert!(mountinfo.find(Path::new("/")).unwrap().is_shared());
+        assert!(!mountinfo.find(Path::new("/run/shm")).unwrap().is_shared());
+        assert_eq!(mountinfo.below(Path::new("/run")).count(), 2);
+    }
+}
@@ -0,0 +1,378 @@
+use anyhow::{anyhow, bail, Context};
+use nix::mount::MsFlags;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::effects::{self, describe_mount};
+use nixos_wsl_utils::mountinfo::MountInfo;
+
+/// A single mount(2) call
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mount {
+    pub source: Option<PathBuf>,
+    pub target: PathBuf,
+    pub fstype: Option<&'static str>,
+    pub flags: MsFlags,
+    pub data: Option<String>,
+}
+
+impl Mount {
+    fn describe(&self) -> String {
+        describe_mount(
+            self.source.as_deref(),
+            &self.target,
+            self.fstype,
+            self.flags,
+            self.data.as_deref(),
+        )
+    }
+
+    fn apply(&self) -> anyhow::Result<()> {
+        effects::mount(
+            self.source.as_ref(),
+            &self.target,
+            self.fstype,
+            self.flags,
+            self.data.as_deref(),
+        )
+        .with_context(|| format!("When running {}", self.describe()))
+    }
+
+    /// Paths that have to be in place before this mount can run
+    fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.source
+            .iter()
+            .map(PathBuf::as_path)
+            .filter(|source| source.is_absolute())
+            .chain([self.target.as_path()])
+    }
+}
+
+/// When an operation can be skipped, because the system already looks like it ran
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Done {
+    Never,
+    /// The target is a mount point
+    IfMounted,
+    /// The target is a mount point and read-only, e.g. from an earlier boot
+    IfReadOnly,
+    /// The target and every mount below it have shared propagation
+    IfShared,
+}
+
+impl Done {
+    fn check(self, mount: &Mount, mountinfo: &MountInfo) -> bool {
+        match self {
+            Done::Never => false,
+            Done::IfMounted => mountinfo.is_mountpoint(&mount.target),
+            Done::IfReadOnly => mountinfo
+                .find(&mount.target)
+                .map_or(false, |entry| entry.is_read_only()),
+            Done::IfShared => {
+                mountinfo.is_mountpoint(&mount.target)
+                    && mountinfo
+                        .below(&mount.target)
+                        .all(|entry| entry.is_shared())
+            }
+        }
+    }
+}
+
+pub struct MountOp {
+    pub name: &'static str,
+    /// Operations that have to succeed first. If one of them fails, this one is skipped.
+    pub after: &'static [&'static str],
+    pub mount: Mount,
+    pub done: Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpResult {
+    Mounted,
+    /// Skipped, the mount was already in place
+    AlreadyDone,
+    Failed,
+    /// An operation it depends on failed
+    Blocked,
+}
+
+/// A set of mounts that are run in dependency order, with independent ones running in parallel
+pub struct MountPlan {
+    ops: Vec<MountOp>,
+    /// Indices of the operations each operation waits for
+    deps: Vec<Vec<usize>>,
+}
+
+impl MountPlan {
+    /// Checks that names are unique and dependencies refer to earlier operations.
+    /// Operations on nested paths are ordered as well, even without `after`, so nothing gets
+    /// mounted on top of or below a mount that is still in progress.
+    pub fn new(ops: Vec<MountOp>) -> anyhow::Result<MountPlan> {
+        let mut deps = vec![];
+        for (idx, op) in ops.iter().enumerate() {
+            let earlier = &ops[..idx];
+            if earlier.iter().any(|o| o.name == op.name) {
+                bail!("duplicate mount {}", op.name);
+            }
+            let mut op_deps = HashSet::new();
+            for dep in op.after {
+                let pos = earlier.iter().position(|o| o.name == *dep).ok_or(anyhow!(
+                    "mount {} has to run after unknown mount {}",
+                    op.name,
+                    dep
+                ))?;
+                op_deps.insert(pos);
+            }
+            for (pos, other) in earlier.iter().enumerate() {
+                let target = &other.mount.target;
+                if op
+                    .mount
+                    .paths()
+                    .any(|path| path.starts_with(target) || target.starts_with(path))
+                {
+                    op_deps.insert(pos);
+                }
+            }
+            let mut op_deps: Vec<usize> = op_deps.into_iter().collect();
+            op_deps.sort();
+            deps.push(op_deps);
+        }
+        Ok(MountPlan { ops, deps })
+    }
+
+    pub fn run(&self) -> anyhow::Result<Vec<(&'static str, OpResult)>> {
+        self.run_with(MountInfo::read, Mount::apply)
+    }
+
+    /// Runs the plan in rounds: each round runs every operation whose dependencies are done, in parallel.
+    /// Returns the first error, after everything that doesn't depend on the failed operation has run.
+    fn run_with(
+        &self,
+        mountinfo: impl Fn() -> anyhow::Result<MountInfo>,
+        apply: impl Fn(&Mount) -> anyhow::Result<()> + Sync,
+    ) -> anyhow::Result<Vec<(&'static str, OpResult)>> {
+        let mut results: Vec<Option<OpResult>> = vec![None; self.ops.len()];
+        let mut first_error = None;
+
+        while results.iter().any(Option::is_none) {
+            let mountinfo = mountinfo()?;
+            let mut ready = vec![];
+            for (idx, op) in self.ops.iter().enumerate() {
+                if results[idx].is_some() {
+                    continue;
+                }
+                let deps = &self.deps[idx];
+                if let Some(dep) = deps.iter().find(|dep| {
+                    matches!(results[**dep], Some(OpResult::Failed | OpResult::Blocked))
+                }) {
+                    log::warn!(
+                        "Skipping mount {}, because {} failed",
+                        op.name,
+                        self.ops[*dep].name
+                    );
+                    results[idx] = Some(OpResult::Blocked);
+                } else if deps.iter().all(|dep| results[*dep].is_some()) {
+                    if op.done.check(&op.mount, &mountinfo) {
+                        log::trace!("Mount {} is already in place, skipping...", op.name);
+                        results[idx] = Some(OpResult::AlreadyDone);
+                    } else {
+                        ready.push(idx);
+                    }
+                }
+            }
+
+            let apply = &apply;
+            let outcomes: Vec<anyhow::Result<()>> = thread::scope(|scope| {
+                let handles: Vec<_> = ready
+                    .iter()
+                    .map(|&idx| {
+                        let op = &self.ops[idx];
+                        scope.spawn(move || {
+                            log::trace!("Mounting {} ({})...", op.name, op.mount.describe());
+                            apply(&op.mount)
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle
+                            .join()
+                            .unwrap_or_else(|_| Err(anyhow!("mount thread panicked")))
+                    })
+                    .collect()
+            });
+
+            for (idx, outcome) in ready.into_iter().zip(outcomes) {
+                results[idx] = Some(match outcome {
+                    Ok(()) => OpResult::Mounted,
+                    Err(e) => {
+                        // The first error is returned, so only the others are logged here
+                        if first_error.is_some() {
+                            log::error!("Mount {} failed: {:?}", self.ops[idx].name, e);
+                        } else {
+                            first_error = Some(e);
+                        }
+                        OpResult::Failed
+                    }
+                });
+            }
+        }
+
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+        Ok(self
+            .ops
+            .iter()
+            .zip(results)
+            .map(|(op, result)| (op.name, result.unwrap_or(OpResult::Blocked)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn op(name: &'static str, target: &str, after: &'static [&'static str]) -> MountOp {
+        MountOp {
+            name,
+            after,
+            mount: Mount {
+                source: None,
+                target: PathBuf::from(target),
+                fstype: Some("tmpfs"),
+                flags: MsFlags::empty(),
+                data: None,
+            },
+            done: Done::IfMounted,
+        }
+    }
+
+    fn no_mounts() -> anyhow::Result<MountInfo> {
+        Ok(MountInfo::default())
+    }
+
+    #[test]
+    fn rejects_bad_dependencies() {
+        assert!(MountPlan::new(vec![op("a", "/a", &[]), op("a", "/b", &[])]).is_err());
+        assert!(MountPlan::new(vec![op("a", "/a", &["b"]), op("b", "/b", &[])]).is_err());
+    }
+
+    #[test]
+    fn nested_targets_are_ordered() {
+        let mut bind = op("bind", "/run/shm", &[]);
+        bind.mount.source = Some(PathBuf::from("/dev/shm"));
+        let plan = MountPlan::new(vec![
+            op("shm", "/dev/shm", &[]),
+            op("store", "/nix/store", &[]),
+            op("store-ro", "/nix/store", &[]),
+            op("hugepages", "/dev/hugepages", &[]),
+            bind,
+        ])
+        .unwrap();
+        assert_eq!(plan.deps, vec![vec![], vec![], vec![1], vec![], vec![0]]);
+    }
+
+    #[test]
+    fn independent_mounts_run_in_parallel() {
+        let plan = MountPlan::new(vec![op("a", "/a", &[]), op("b", "/b", &[])]).unwrap();
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        plan.run_with(no_mounts, |_| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            most.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn dependencies_run_first() {
+        let plan = MountPlan::new(vec![
+            op("a", "/a", &[]),
+            op("b", "/b", &["a"]),
+            op("c", "/c", &["b"]),
+        ])
+        .unwrap();
+        let order = Mutex::new(vec![]);
+        plan.run_with(no_mounts, |mount| {
+            order.lock().unwrap().push(mount.target.clone());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            order.into_inner().unwrap(),
+            vec![
+                PathBuf::from("/a"),
+                PathBuf::from("/b"),
+                PathBuf::from("/c")
+            ]
+        );
+    }
+
+    #[test]
+    fn existing_mounts_are_skipped() {
+        let plan = MountPlan::new(vec![op("a", "/a", &[]), op("b", "/b", &["a"])]).unwrap();
+        let results = plan
+            .run_with(
+                || Ok(MountInfo::parse("36 22 0:31 / /a rw - tmpfs tmpfs rw\n")),
+                |mount| {
+                    assert_eq!(mount.target, PathBuf::from("/b"));
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![("a", OpResult::AlreadyDone), ("b", OpResult::Mounted)]
+        );
+    }
+
+    #[test]
+    fn checks_flags_and_propagation() {
+        let mountinfo = MountInfo::parse(
+            "22 1 8:32 / / rw shared:1 - ext4 /dev/sdc rw\n\
+             41 22 8:32 /nix/store /nix/store ro shared:1 - ext4 /dev/sdc rw\n\
+             43 22 0:40 / /mnt/c rw - 9p drvfs rw\n",
+        );
+        let mount = |target: &str| op("test", target, &[]).mount;
+        assert!(Done::IfReadOnly.check(&mount("/nix/store"), &mountinfo));
+        assert!(!Done::IfReadOnly.check(&mount("/mnt/c"), &mountinfo));
+        assert!(!Done::IfReadOnly.check(&mount("/nix/var"), &mountinfo));
+        assert!(Done::IfShared.check(&mount("/nix/store"), &mountinfo));
+        // /mnt/c is private
+        assert!(!Done::IfShared.check(&mount("/"), &mountinfo));
+    }
+
+    #[test]
+    fn failures_block_dependents_only() {
+        let plan = MountPlan::new(vec![
+            op("a", "/a", &[]),
+            op("b", "/b", &["a"]),
+            op("c", "/c", &[]),
+        ])
+        .unwrap();
+        let ran = Mutex::new(vec![]);
+        let result = plan.run_with(no_mounts, |mount| {
+            ran.lock().unwrap().push(mount.target.clone());
+            if mount.target == Path::new("/a") {
+                Err(anyhow!("a failed"))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(result.is_err());
+        let mut ran = ran.into_inner().unwrap();
+        ran.sort();
+        assert_eq!(ran, vec![PathBuf::from("/a"), PathBuf::from("/c")]);
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::bail;
+use std::collections::HashMap;
+
+use crate::log_mountinfo;
+use crate::retry::{run_with_retry, Outcome, RetryPolicy};
+
+/// A single boot fixup
+pub struct Step<'a> {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Steps that have to run first. If one of them fails, this step is skipped.
+    pub after: &'static [&'static str],
+    pub policy: RetryPolicy,
+    pub run: Box<dyn FnMut() -> anyhow::Result<()> + 'a>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepResult {
+    Succeeded,
+    /// Failed, but the step's policy allowed booting anyway
+    Failed,
+    /// Turned off in the config, or not selected with --only
+    Disabled,
+    /// A step it depends on failed
+    Blocked,
+}
+
+pub struct Pipeline<'a> {
+    steps: Vec<Step<'a>>,
+}
+
+impl<'a> Pipeline<'a> {
+    /// Checks that step names are unique and dependencies refer to earlier steps
+    pub fn new(steps: Vec<Step<'a>>) -> anyhow::Result<Pipeline<'a>> {
+        for (idx, step) in steps.iter().enumerate() {
+            let earlier = &steps[..idx];
+            if earlier.iter().any(|s| s.name == step.name) {
+                bail!("duplicate step {}", step.name);
+            }
+            for dep in step.after {
+                if !earlier.iter().any(|s| s.name == *dep) {
+                    bail!("step {} has to run after unknown step {}", step.name, dep);
+                }
+            }
+        }
+        Ok(Pipeline { steps })
+    }
+
+    pub fn has_step(&self, name: &str) -> bool {
+        self.steps.iter().any(|s| s.name == name)
+    }
+
+    /// Human readable list of the steps, in the order they run
+    pub fn describe(&self) -> String {
+        let width = self.steps.iter().map(|s| s.name.len()).max().unwrap_or(0);
+        let mut result = String::new();
+        for step in &self.steps {
+            result.push_str(&format!("{:width$}  {}", step.name, step.description));
+            if !step.after.is_empty() {
+                result.push_str(&format!(" (after {})", step.after.join(", ")));
+            }
+            result.push('\n');
+        }
+        result
+    }
+
+    /// Runs all enabled steps in order. Stops at the first step that fails fatally.
+    pub fn run(
+        &mut self,
+        is_enabled: impl Fn(&str) -> bool,
+        trace_mounts: bool,
+    ) -> anyhow::Result<Vec<(&'static str, StepResult)>> {
+        let mut results: HashMap<&'static str, StepResult> = HashMap::new();
+        let mut order = vec![];
+
+        for step in &mut self.steps {
+            let result = if !is_enabled(step.name) {
+                log::trace!("Step {} is disabled, skipping...", step.name);
+                StepResult::Disabled
+            } else if let Some(dep) = step.after.iter().find(|dep| {
+                matches!(
+                    results.get(*dep),
+                    Some(StepResult::Failed | StepResult::Blocked)
+                )
+            }) {
+                log::warn!("Skipping step {}, because {} failed", step.name, dep);
+                StepResult::Blocked
+            } else {
+                if trace_mounts {
+                    log_mountinfo(step.name, "before");
+                }
+                let outcome = run_with_retry(step.name, &step.policy, &mut step.run);
+                if trace_mounts {
+                    log_mountinfo(step.name, "after");
+                }
+                match outcome? {
+                    Outcome::Succeeded => StepResult::Succeeded,
+                    Outcome::Skipped => StepResult::Failed,
+                }
+            };
+            results.insert(step.name, result);
+            order.push((step.name, result));
+        }
+
+        Ok(order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::cell::RefCell;
+
+    const SKIPPABLE: RetryPolicy = RetryPolicy {
+        fatal: false,
+        ..RetryPolicy::FATAL_ONCE
+    };
+
+    fn step<'a>(
+        name: &'static str,
+        after: &'static [&'static str],
+        policy: RetryPolicy,
+        log: &'a RefCell<Vec<&'static str>>,
+        succeed: bool,
+    ) -> Step<'a> {
+        Step {
+            name,
+            description: "test step",
+            after,
+            policy,
+            run: Box::new(move || {
+                log.borrow_mut().push(name);
+                if succeed {
+                    Ok(())
+                } else {
+                    Err(anyhow!("{} failed", name))
+                }
+            }),
+        }
+    }
+
+    #[test]
+    fn runs_enabled_steps_in_order() {
+        let log = RefCell::new(vec![]);
+        let mut pipeline = Pipeline::new(vec![
+            step("a", &[], SKIPPABLE, &log, true),
+            step("b", &[], SKIPPABLE, &log, true),
+            step("c", &["a"], SKIPPABLE, &log, true),
+        ])
+        .unwrap();
+
+        let results = pipeline.run(|name| name != "b", false).unwrap();
+        assert_eq!(*log.borrow(), vec!["a", "c"]);
+        assert_eq!(
+            results,
+            vec![
+                ("a", StepResult::Succeeded),
+                ("b", StepResult::Disabled),
+                ("c", StepResult::Succeeded)
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_dependency_blocks_dependents() {
+        let log = RefCell::new(vec![]);
+        let mut pipeline = Pipeline::new(vec![
+            step("a", &[], SKIPPABLE, &log, false),
+            step("b", &["a"], SKIPPABLE, &log, true),
+            step("c", &["b"], SKIPPABLE, &log, true),
+            step("d", &[], SKIPPABLE, &log, true),
+        ])
+        .unwrap();
+
+        let results = pipeline.run(|_| true, false).unwrap();
+        assert_eq!(*log.borrow(), vec!["a", "d"]);
+        assert_eq!(results[1], ("b", StepResult::Blocked));
+        assert_eq!(results[2], ("c", StepResult::Blocked));
+    }
+
+    #[test]
+    fn disabled_dependency_does_not_block() {
+        let log = RefCell::new(vec![]);
+        let mut pipeline = Pipeline::new(vec![
+            step("a", &[], SKIPPABLE, &log, true),
+            step("b", &["a"], SKIPPABLE, &log, true),
+        ])
+        .unwrap();
+
+        pipeline.run(|name| name == "b", false).unwrap();
+        assert_eq!(*log.borrow(), vec!["b"]);
+    }
+
+    #[test]
+    fn fatal_failure_stops_the_pipeline() {
+        let log = RefCell::new(vec![]);
+        let mut pipeline = Pipeline::new(vec![
+            step("a", &[], RetryPolicy::FATAL_ONCE, &log, false),
+            step("b", &[], SKIPPABLE, &log, true),
+        ])
+        .unwrap();
+
+        assert!(pipeline.run(|_| true, false).is_err());
+        assert_eq!(*log.borrow(), vec!["a"]);
+    }
+
+    #[test]
+    fn rejects_invalid_dependencies() {
+        let log = RefCell::new(vec![]);
+        assert!(Pipeline::new(vec![
+            step("a", &["b"], SKIPPABLE, &log, true),
+            step("b", &[], SKIPPABLE, &log, true),
+        ])
+        .is_err());
+        assert!(Pipeline::new(vec![
+            step("a", &[], SKIPPABLE, &log, true),
+            step("a", &[], SKIPPABLE, &log, true),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn describes_steps() {
+        let log = RefCell::new(vec![]);
+        let pipeline = Pipeline::new(vec![
+            step("a", &[], SKIPPABLE, &log, true),
+            step("bbb", &["a"], SKIPPABLE, &log, true),
+        ])
+        .unwrap();
+        assert_eq!(
+            pipeline.describe(),
+            "a    test step\nbbb  test step (after a)\n"
+        );
+        assert!(pipeline.has_step("bbb"));
+        assert!(!pipeline.has_step("c"));
+    }
+}
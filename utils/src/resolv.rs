@@ -0,0 +1,343 @@
+#[cfg(test)]
+mod test_util;
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use nix::errno::Errno;
+use nix::libc::{RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_IFADDR, RTMGRP_LINK};
+use nix::sys::socket::{
+    bind, recv, setsockopt, socket, sockopt::ReceiveTimeout, AddressFamily, MsgFlags, NetlinkAddr,
+    SockFlag, SockProtocol, SockType,
+};
+use nix::sys::time::TimeVal;
+use std::env;
+use std::fs::{
+    create_dir_all, metadata, read_to_string, rename, set_permissions, write, Permissions,
+};
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+use nixos_wsl_utils::interop::{run_with_timeout, use_live_socket};
+
+/// Writes resolv.conf from the DNS servers Windows uses, including the ones of VPN connections.
+/// The resolv.conf WSL generates points at the NAT gateway, which often can't reach servers that only a VPN adapter knows about.
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-resolv", version = build_info::VERSION)]
+struct Args {
+    #[arg(long, default_value = "/etc/resolv.conf")]
+    output: PathBuf,
+
+    /// Where the last answer from Windows is kept, for when asking again fails
+    #[arg(long, default_value = "/var/cache/nixos-wsl/resolv.conf")]
+    cache: PathBuf,
+
+    /// How long the cached answer is used without asking Windows again, in seconds. Ignored with --watch.
+    #[arg(long, default_value = "300")]
+    cache_ttl: u64,
+
+    #[arg(long, default_value = "powershell.exe")]
+    powershell: PathBuf,
+
+    /// How long to wait for PowerShell, in seconds
+    #[arg(long, default_value = "15")]
+    timeout: u64,
+
+    /// Keep running and update resolv.conf whenever the network changes
+    #[arg(long)]
+    watch: bool,
+
+    /// With --watch, also ask Windows this often, in seconds.
+    /// A VPN connecting on Windows doesn't show up as a network change in NAT mode.
+    #[arg(long, default_value = "60")]
+    interval: u64,
+
+    /// Print the generated resolv.conf instead of writing it
+    #[arg(long)]
+    print: bool,
+}
+
+/// Prints `nameserver` and `search` lines, with the servers of the adapters with the lowest metric (usually VPNs) first
+const DNS_SCRIPT: &str = "$metrics = @{}; \
+    Get-NetIPInterface -AddressFamily IPv4 | ForEach-Object { $metrics[$_.ifIndex] = $_.InterfaceMetric }; \
+    Get-DnsClientServerAddress -AddressFamily IPv4 | Where-Object { $_.ServerAddresses } | \
+    Sort-Object { $metrics[$_.InterfaceIndex] } | ForEach-Object { $_.ServerAddresses } | \
+    ForEach-Object { \"nameserver $_\" }; \
+    (Get-DnsClientGlobalSetting).SuffixSearchList | ForEach-Object { \"search $_\" }";
+
+/// The resolver only uses this many nameservers, see resolv.conf(5)
+const MAXNS: usize = 3;
+
+/// How long to wait for a burst of network changes to end before asking Windows
+const SETTLE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Default, PartialEq)]
+struct DnsSettings {
+    nameservers: Vec<Ipv4Addr>,
+    search: Vec<String>,
+}
+
+/// Parses the output of DNS_SCRIPT, skipping anything that doesn't look right
+fn parse_settings(output: &str) -> DnsSettings {
+    let mut settings = DnsSettings::default();
+    for line in output.lines() {
+        match line.trim().split_once(' ') {
+            Some(("nameserver", address)) => {
+                let Ok(address) = address.trim().parse::<Ipv4Addr>() else {
+                    continue;
+                };
+                // The distro can't reach these on the Windows side in NAT mode
+                if address.is_loopback() || address.is_unspecified() {
+                    continue;
+                }
+                if !settings.nameservers.contains(&address) {
+                    settings.nameservers.push(address);
+                }
+            }
+            Some(("search", domain)) => {
+                let domain = domain.trim().to_string();
+                if !domain.is_empty() && !domain.contains(' ') && !settings.search.contains(&domain)
+                {
+                    settings.search.push(domain);
+                }
+            }
+            _ => {}
+        }
+    }
+    settings
+}
+
+fn render(settings: &DnsSettings) -> anyhow::Result<String> {
+    if settings.nameservers.is_empty() {
+        bail!("Windows doesn't have any IPv4 DNS servers");
+    }
+    let mut result =
+        "# Generated by nixos-wsl-resolv from the DNS settings of Windows\n".to_string();
+    for address in settings.nameservers.iter().take(MAXNS) {
+        result.push_str(&format!("nameserver {}\n", address));
+    }
+    if !settings.search.is_empty() {
+        result.push_str(&format!("search {}\n", settings.search.join(" ")));
+    }
+    Ok(result)
+}
+
+fn query_windows(powershell: &Path, timeout: Duration) -> anyhow::Result<DnsSettings> {
+    let mut command = Command::new(powershell);
+    command.args(["-NoProfile", "-NonInteractive", "-Command", DNS_SCRIPT]);
+    use_live_socket(&mut command);
+    let output = run_with_timeout(&mut command, timeout)?;
+    if !output.status.success() {
+        bail!(
+            "PowerShell failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(parse_settings(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Replaces `path` with `content` in one go, so resolvers never see a half-written file.
+/// Returns whether anything changed.
+fn write_atomic(path: &Path, content: &str) -> anyhow::Result<bool> {
+    if read_to_string(path).map_or(false, |old| old == content) {
+        return Ok(false);
+    }
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).with_context(|| format!("When creating {}", parent.display()))?;
+    }
+    let temp = path.with_file_name(format!(
+        ".{}.tmp",
+        path.file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("resolv.conf")
+    ));
+    write(&temp, content).with_context(|| format!("When writing {}", temp.display()))?;
+    set_permissions(&temp, Permissions::from_mode(0o644))
+        .with_context(|| format!("When setting the mode of {}", temp.display()))?;
+    // Replaces a symlink too, e.g. one left behind by resolvconf
+    rename(&temp, path).with_context(|| format!("When replacing {}", path.display()))?;
+    Ok(true)
+}
+
+fn is_fresh(path: &Path, ttl: Duration) -> bool {
+    metadata(path)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .map_or(false, |age| age < ttl)
+}
+
+/// The resolv.conf for the current Windows settings, from the cache if it is fresh or Windows doesn't answer
+fn resolv_conf(args: &Args, use_cache: bool) -> anyhow::Result<String> {
+    if use_cache && is_fresh(&args.cache, Duration::from_secs(args.cache_ttl)) {
+        if let Ok(cached) = read_to_string(&args.cache) {
+            return Ok(cached);
+        }
+    }
+
+    let queried = query_windows(&args.powershell, Duration::from_secs(args.timeout))
+        .and_then(|settings| render(&settings));
+    match queried {
+        Ok(content) => {
+            if let Err(e) = write_atomic(&args.cache, &content) {
+                eprintln!("Warning: {:?}", e);
+            }
+            Ok(content)
+        }
+        Err(e) => match read_to_string(&args.cache) {
+            Ok(cached) => {
+                eprintln!(
+                    "Warning: {:#}, using the settings cached in {}",
+                    e,
+                    args.cache.display()
+                );
+                Ok(cached)
+            }
+            Err(_) => Err(e),
+        },
+    }
+}
+
+fn apply(args: &Args, use_cache: bool) -> anyhow::Result<()> {
+    let content = resolv_conf(args, use_cache)?;
+    if args.print {
+        print!("{}", content);
+    } else if write_atomic(&args.output, &content)? {
+        println!("Updated {}", args.output.display());
+    }
+    Ok(())
+}
+
+/// A netlink socket that receives changes to links, addresses and routes, see rtnetlink(7)
+fn network_events() -> anyhow::Result<OwnedFd> {
+    let socket = socket(
+        AddressFamily::Netlink,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC,
+        SockProtocol::NetlinkRoute,
+    )
+    .context("When opening a netlink socket")?;
+    let groups = RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_IFADDR;
+    bind(socket.as_raw_fd(), &NetlinkAddr::new(0, groups as u32))
+        .context("When subscribing to network changes")?;
+    Ok(socket)
+}
+
+/// Waits until the network changes or `timeout` passes. Returns whether it changed.
+fn wait_for_change(socket: &OwnedFd, timeout: Duration) -> anyhow::Result<bool> {
+    setsockopt(
+        socket,
+        ReceiveTimeout,
+        &TimeVal::new(timeout.as_secs() as _, 0),
+    )
+    .context("When setting the netlink timeout")?;
+    let mut buf = [0u8; 8192];
+    match recv(socket.as_raw_fd(), &mut buf, MsgFlags::empty()) {
+        // Too many changes to queue them all still means something changed
+        Ok(_) | Err(Errno::ENOBUFS) => Ok(true),
+        Err(Errno::EAGAIN | Errno::EINTR) => Ok(false),
+        Err(e) => Err(e).context("When waiting for network changes"),
+    }
+}
+
+fn watch(args: &Args) -> anyhow::Result<()> {
+    let socket = network_events()?;
+    loop {
+        // A failed update is retried on the next change, so keep running
+        if let Err(e) = apply(args, false) {
+            eprintln!("Error: {:?}", e);
+        }
+        if wait_for_change(&socket, Duration::from_secs(args.interval))? {
+            while wait_for_change(&socket, SETTLE)? {}
+        }
+    }
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    if args.watch {
+        watch(&args)
+    } else {
+        apply(&args, true)
+    }
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::fs::remove_dir_all;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn parses_powershell_output() {
+        let output = "nameserver 10.8.0.1\r\nnameserver 192.168.1.1\r\nnameserver 10.8.0.1\r\n\
+                      nameserver 127.0.0.1\r\nnameserver fec0:0:0:ffff::1\r\n\
+                      search corp.example.com\r\nsearch \r\nWARNING: something\r\n";
+        assert_eq!(
+            parse_settings(output),
+            DnsSettings {
+                nameservers: vec![Ipv4Addr::new(10, 8, 0, 1), Ipv4Addr::new(192, 168, 1, 1)],
+                search: vec!["corp.example.com".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn renders_resolv_conf() {
+        let settings = DnsSettings {
+            nameservers: (1..=4).map(|n| Ipv4Addr::new(10, 0, 0, n)).collect(),
+            search: vec!["a.example".to_string(), "b.example".to_string()],
+        };
+        assert_eq!(
+            render(&settings).unwrap(),
+            "# Generated by nixos-wsl-resolv from the DNS settings of Windows\n\
+             nameserver 10.0.0.1\nnameserver 10.0.0.2\nnameserver 10.0.0.3\n\
+             search a.example b.example\n"
+        );
+        assert!(render(&DnsSettings::default()).is_err());
+    }
+
+    #[test]
+    fn writes_atomically() {
+        let dir = scratch_dir("resolv");
+        let path = dir.join("resolv.conf");
+        symlink(dir.join("elsewhere"), &path).unwrap();
+        assert!(write_atomic(&path, "nameserver 10.0.0.1\n").unwrap());
+        assert!(!metadata(&path).unwrap().file_type().is_symlink());
+        assert!(!write_atomic(&path, "nameserver 10.0.0.1\n").unwrap());
+        assert_eq!(read_to_string(&path).unwrap(), "nameserver 10.0.0.1\n");
+        assert!(!dir.join(".resolv.conf.tmp").exists());
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn falls_back_to_the_cache() {
+        let dir = scratch_dir("resolv-cache");
+        let args = Args::parse_from([
+            "nixos-wsl-resolv",
+            "--cache",
+            dir.join("cache").to_str().unwrap(),
+            "--powershell",
+            dir.join("no-powershell").to_str().unwrap(),
+        ]);
+        assert!(resolv_conf(&args, false).is_err());
+        write(&args.cache, "nameserver 10.0.0.1\n").unwrap();
+        assert!(is_fresh(&args.cache, Duration::from_secs(300)));
+        assert_eq!(resolv_conf(&args, false).unwrap(), "nameserver 10.0.0.1\n");
+        remove_dir_all(dir).unwrap();
+    }
+}
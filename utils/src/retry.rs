@@ -0,0 +1,173 @@
+use nix::errno::Errno;
+use std::fmt;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// How a boot step is retried and what happens if it keeps failing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of tries, including the first one
+    pub attempts: u32,
+    /// Delay before the first retry, doubled after every further failure
+    pub backoff: Duration,
+    /// Whether a step that still fails after all attempts aborts the boot, or is skipped with a warning
+    pub fatal: bool,
+}
+
+impl RetryPolicy {
+    /// Run once and abort the boot on failure
+    pub const FATAL_ONCE: RetryPolicy = RetryPolicy {
+        attempts: 1,
+        backoff: Duration::ZERO,
+        fatal: true,
+    };
+
+    fn delay(&self, retry: u32) -> Duration {
+        // Cap the shift so a misconfigured policy can't overflow
+        self.backoff * 2u32.pow(retry.min(16))
+    }
+}
+
+/// Errors that are expected to go away on their own while WSL's init is still busy setting things up.
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let errno = if let Some(errno) = cause.downcast_ref::<Errno>() {
+            Some(*errno)
+        } else {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(|e| e.raw_os_error())
+                .map(Errno::from_raw)
+        };
+        matches!(
+            errno,
+            Some(Errno::EBUSY | Errno::ENOENT | Errno::EAGAIN | Errno::EINTR)
+        )
+    })
+}
+
+/// Context attached to fatal step errors, so callers can tell which step aborted the boot
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepFailed(pub String);
+
+impl fmt::Display for StepFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Step {} failed", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Succeeded,
+    /// Failed, but the policy says that's not fatal
+    Skipped,
+}
+
+/// Runs `f` according to `policy`.
+/// Only returns an error if the step failed and the failure is fatal.
+pub fn run_with_retry(
+    name: &str,
+    policy: &RetryPolicy,
+    mut f: impl FnMut() -> anyhow::Result<()>,
+) -> anyhow::Result<Outcome> {
+    let mut attempt = 1;
+    loop {
+        let err = match f() {
+            Ok(()) => return Ok(Outcome::Succeeded),
+            Err(err) => err,
+        };
+
+        if attempt < policy.attempts && is_transient(&err) {
+            let delay = policy.delay(attempt - 1);
+            log::warn!(
+                "Step {} failed (attempt {}/{}), retrying in {:?}: {:?}",
+                name,
+                attempt,
+                policy.attempts,
+                delay,
+                err
+            );
+            sleep(delay);
+            attempt += 1;
+        } else if policy.fatal {
+            return Err(err.context(StepFailed(name.to_string())));
+        } else {
+            log::error!("Step {} failed, skipping: {:?}", name, err);
+            return Ok(Outcome::Skipped);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    const QUICK: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        backoff: Duration::ZERO,
+        fatal: true,
+    };
+
+    #[test]
+    fn backoff_doubles() {
+        let policy = RetryPolicy {
+            backoff: Duration::from_millis(10),
+            ..QUICK
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(10));
+        assert_eq!(policy.delay(2), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let mut calls = 0;
+        let result = run_with_retry("test", &QUICK, || {
+            calls += 1;
+            if calls < 3 {
+                Err(Errno::EBUSY).context("When mounting")
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(result.unwrap(), Outcome::Succeeded);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn io_errors_are_classified() {
+        let err = anyhow::Error::from(std::io::Error::from_raw_os_error(Errno::ENOENT as i32));
+        assert!(is_transient(&err));
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let mut calls = 0;
+        let result = run_with_retry("test", &QUICK, || {
+            calls += 1;
+            Err(Errno::EINVAL).context("When mounting")
+        });
+        let err = result.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<StepFailed>(),
+            Some(&StepFailed("test".to_string()))
+        );
+        assert_eq!(err.to_string(), "Step test failed");
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn skippable_step_is_ok_after_exhausting_attempts() {
+        let mut calls = 0;
+        let policy = RetryPolicy {
+            fatal: false,
+            ..QUICK
+        };
+        let result = run_with_retry("test", &policy, || {
+            calls += 1;
+            Err(anyhow!(Errno::ENOENT))
+        });
+        assert_eq!(result.unwrap(), Outcome::Skipped);
+        assert_eq!(calls, 3);
+    }
+}
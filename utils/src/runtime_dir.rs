@@ -0,0 +1,203 @@
+#[cfg(test)]
+mod test_util;
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use nix::unistd::{chown, getuid, Uid, User};
+use std::collections::HashMap;
+use std::env;
+use std::fs::{create_dir, metadata, remove_file, set_permissions, symlink_metadata, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+use nixos_wsl_utils::quote::shell_quote;
+
+/// Creates the XDG runtime directory of a user if systemd-logind didn't, and links the WSLg sockets into it.
+/// Shells started with `wsl.exe --exec`, su or sudo don't go through logind, so they end up without one.
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-runtime-dir", version = build_info::VERSION)]
+struct Args {
+    /// Defaults to the calling user
+    #[arg(long)]
+    uid: Option<u32>,
+
+    #[arg(long, default_value = "/run/user")]
+    runtime_root: PathBuf,
+
+    #[arg(long, default_value = "/mnt/wslg/runtime-dir")]
+    wslg_runtime_dir: PathBuf,
+
+    /// Print shell exports for XDG_RUNTIME_DIR and the variables of the linked sockets
+    #[arg(long)]
+    print_env: bool,
+}
+
+/// Sockets WSLg creates in its runtime directory that clients look for in XDG_RUNTIME_DIR
+const WSLG_SOCKETS: &[&str] = &["wayland-0", "wayland-0.lock", "pulse"];
+
+const RUNTIME_DIR_MODE: u32 = 0o700;
+
+/// Creates `dir` owned by `uid` if it is missing, and fixes its mode and owner if we are allowed to
+fn ensure_dir(dir: &Path, uid: Uid) -> anyhow::Result<()> {
+    match create_dir(dir) {
+        Ok(()) => {
+            let gid = User::from_uid(uid)
+                .context("When looking up the user")?
+                .map(|user| user.gid);
+            chown(dir, Some(uid), gid)
+                .with_context(|| format!("When changing the owner of {}", dir.display()))?;
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            bail!("{} is missing and only root can create it", dir.display())
+        }
+        Err(e) => return Err(e).with_context(|| format!("When creating {}", dir.display())),
+    }
+
+    let meta = metadata(dir).with_context(|| format!("When checking {}", dir.display()))?;
+    if meta.uid() != uid.as_raw() {
+        bail!(
+            "{} belongs to uid {}, not {}",
+            dir.display(),
+            meta.uid(),
+            uid
+        );
+    }
+    if meta.mode() & 0o777 != RUNTIME_DIR_MODE {
+        set_permissions(dir, Permissions::from_mode(RUNTIME_DIR_MODE))
+            .with_context(|| format!("When setting the mode of {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Links the WSLg sockets into `dir`, leaving alone whatever is there already.
+/// Returns the names that are linked now.
+fn link_wslg_sockets(dir: &Path, wslg: &Path) -> anyhow::Result<Vec<&'static str>> {
+    let mut linked = vec![];
+    for name in WSLG_SOCKETS {
+        let source = wslg.join(name);
+        let target = dir.join(name);
+        if symlink_metadata(&source).is_err() {
+            continue;
+        }
+        match symlink_metadata(&target) {
+            // Dangling, left behind by an earlier WSLg session
+            Ok(meta) if meta.file_type().is_symlink() && !target.exists() => {
+                remove_file(&target)
+                    .with_context(|| format!("When removing {}", target.display()))?;
+            }
+            Ok(_) => continue,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("When checking {}", target.display())),
+        }
+        symlink(&source, &target).with_context(|| {
+            format!("When linking {} to {}", target.display(), source.display())
+        })?;
+        linked.push(*name);
+    }
+    Ok(linked)
+}
+
+/// Exports for the runtime directory and the sockets in it, except for variables that are already set
+fn exports(dir: &Path, environment: &HashMap<String, String>) -> String {
+    let mut vars = vec![];
+    if dir.join("wayland-0").exists() {
+        vars.push(("WAYLAND_DISPLAY", "wayland-0".to_string()));
+    }
+    if dir.join("bus").exists() {
+        vars.push((
+            "DBUS_SESSION_BUS_ADDRESS",
+            format!("unix:path={}", dir.join("bus").display()),
+        ));
+    }
+
+    let mut result = format!(
+        "export XDG_RUNTIME_DIR={}\n",
+        shell_quote(&dir.display().to_string())
+    );
+    for (var, value) in vars {
+        if environment.get(var).map_or(true, |v| v.is_empty()) {
+            result.push_str(&format!("export {}={}\n", var, shell_quote(&value)));
+        }
+    }
+    result
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    let uid = args.uid.map(Uid::from_raw).unwrap_or_else(getuid);
+    let dir = args.runtime_root.join(uid.to_string());
+
+    ensure_dir(&dir, uid)?;
+    link_wslg_sockets(&dir, &args.wslg_runtime_dir)?;
+
+    if args.print_env {
+        print!("{}", exports(&dir, &env::vars().collect()));
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn creates_private_dir() {
+        let root = scratch_dir("runtime-dir");
+        let dir = root.join("1000");
+        ensure_dir(&dir, getuid()).unwrap();
+        set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+        ensure_dir(&dir, getuid()).unwrap();
+        assert_eq!(metadata(&dir).unwrap().mode() & 0o777, RUNTIME_DIR_MODE);
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn links_wslg_sockets() {
+        let root = scratch_dir("runtime-wslg");
+        let (dir, wslg) = (root.join("user"), root.join("wslg"));
+        create_dir_all(&dir).unwrap();
+        create_dir_all(wslg.join("pulse")).unwrap();
+        write(wslg.join("wayland-0"), "").unwrap();
+        // A user's own PulseAudio wins
+        create_dir_all(dir.join("pulse")).unwrap();
+        // Left behind by an earlier WSLg session
+        symlink(root.join("gone"), dir.join("wayland-0")).unwrap();
+
+        assert_eq!(link_wslg_sockets(&dir, &wslg).unwrap(), vec!["wayland-0"]);
+        assert_eq!(
+            std::fs::read_link(dir.join("wayland-0")).unwrap(),
+            wslg.join("wayland-0")
+        );
+        assert!(link_wslg_sockets(&dir, &wslg).unwrap().is_empty());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn exports_unset_variables() {
+        let root = scratch_dir("runtime-env");
+        write(root.join("wayland-0"), "").unwrap();
+        write(root.join("bus"), "").unwrap();
+        let environment = HashMap::from([("WAYLAND_DISPLAY".to_string(), "wayland-1".to_string())]);
+        assert_eq!(
+            exports(&root, &environment),
+            format!(
+                "export XDG_RUNTIME_DIR='{0}'\nexport DBUS_SESSION_BUS_ADDRESS='unix:path={0}/bus'\n",
+                root.display()
+            )
+        );
+        remove_dir_all(root).unwrap();
+    }
+}
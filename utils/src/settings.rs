@@ -0,0 +1,329 @@
+use anyhow::{anyhow, bail, Context};
+use clap::{Parser, Subcommand, ValueEnum};
+use nix::unistd::User;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+use nixos_wsl_utils::interop::use_live_socket;
+
+/// Reads and changes the settings WSL keeps for a distro in the Windows registry, through reg.exe.
+/// WSL reads them when the distro starts, so changes apply after `wsl --terminate`.
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-settings", version = build_info::VERSION)]
+struct Args {
+    /// Defaults to the distro this runs in
+    #[arg(long)]
+    distro: Option<String>,
+
+    #[arg(long, default_value = "reg.exe")]
+    reg: PathBuf,
+
+    #[command(subcommand)]
+    command: SettingsCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum SettingsCommand {
+    /// Print every setting
+    List,
+    Get {
+        setting: Setting,
+    },
+    Set {
+        setting: Setting,
+        /// For default-uid, a user name works as well
+        value: String,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Setting {
+    /// The user `wsl.exe` starts shells as. `[user] default` in wsl.conf (wsl.defaultUser) wins over it.
+    DefaultUid,
+    /// 1: interop, 2: Windows PATH in PATH, 4: Windows drives mounted, 8: WSL 2 (can't be changed)
+    Flags,
+    /// What `wsl.exe -d` calls the distro
+    Name,
+}
+
+impl Setting {
+    const ALL: [Setting; 3] = [Setting::DefaultUid, Setting::Flags, Setting::Name];
+
+    fn name(self) -> &'static str {
+        match self {
+            Setting::DefaultUid => "default-uid",
+            Setting::Flags => "flags",
+            Setting::Name => "name",
+        }
+    }
+
+    /// The name of the registry value
+    fn value_name(self) -> &'static str {
+        match self {
+            Setting::DefaultUid => "DefaultUid",
+            Setting::Flags => "Flags",
+            Setting::Name => "DistributionName",
+        }
+    }
+
+    fn value_type(self) -> &'static str {
+        match self {
+            Setting::DefaultUid | Setting::Flags => "REG_DWORD",
+            Setting::Name => "REG_SZ",
+        }
+    }
+}
+
+/// Where WSL keeps one subkey per distro, named after its GUID
+const LXSS_KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Lxss";
+
+/// The flags that can be turned on and off, see WSL_DISTRIBUTION_FLAGS
+const CHANGEABLE_FLAGS: u32 = 0x7;
+
+/// Splits a value line of `reg query`, like `    DefaultUid    REG_DWORD    0x3e8`, into name, type and data
+fn parse_value(line: &str) -> Option<(&str, &str, &str)> {
+    let line = line.trim_end_matches('\r');
+    let rest = line.strip_prefix("    ")?;
+    let mut fields = rest.splitn(3, "    ");
+    let name = fields.next()?;
+    let value_type = fields.next()?;
+    let data = fields.next().unwrap_or("");
+    Some((name, value_type, data))
+}
+
+/// Finds the key of `distro` in the output of `reg query LXSS_KEY /s /v DistributionName`
+fn find_distro_key(output: &str, distro: &str) -> Option<String> {
+    let mut key = None;
+    for line in output.lines() {
+        if line.starts_with("HKEY_") {
+            key = Some(line.trim_end_matches('\r'));
+        } else if let Some(("DistributionName", _, name)) = parse_value(line) {
+            // WSL compares distro names without case
+            if name.eq_ignore_ascii_case(distro) {
+                return key.map(String::from);
+            }
+        }
+    }
+    None
+}
+
+/// Parses DWORD data, which reg.exe prints as hex
+fn parse_dword(data: &str) -> anyhow::Result<u32> {
+    let digits = data
+        .strip_prefix("0x")
+        .ok_or(anyhow!("not a DWORD: {:?}", data))?;
+    u32::from_str_radix(digits, 16).with_context(|| format!("When parsing DWORD {:?}", data))
+}
+
+fn parse_number(value: &str) -> anyhow::Result<u32> {
+    match value.strip_prefix("0x") {
+        Some(digits) => u32::from_str_radix(digits, 16),
+        None => value.parse(),
+    }
+    .with_context(|| format!("{:?} is not a number", value))
+}
+
+/// Checks `value` and turns it into the data to store for `setting`.
+/// `current` is what is stored now, for settings that can only partly change.
+fn registry_data(setting: Setting, value: &str, current: &str) -> anyhow::Result<String> {
+    match setting {
+        Setting::DefaultUid => {
+            if let Ok(uid) = parse_number(value) {
+                return Ok(uid.to_string());
+            }
+            let user = User::from_name(value)
+                .context("When looking up the user")?
+                .ok_or(anyhow!("{} is neither a UID nor a user", value))?;
+            Ok(user.uid.to_string())
+        }
+        Setting::Flags => {
+            let flags = parse_number(value)?;
+            let current = parse_dword(current)?;
+            if (flags ^ current) & !CHANGEABLE_FLAGS != 0 {
+                bail!(
+                    "Only the interop, PATH and drive mounting flags ({:#x}) can be changed",
+                    CHANGEABLE_FLAGS
+                );
+            }
+            Ok(flags.to_string())
+        }
+        Setting::Name => {
+            if value.is_empty()
+                || !value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+            {
+                bail!("Distro names can only contain letters, digits, '.', '_' and '-'");
+            }
+            Ok(value.to_string())
+        }
+    }
+}
+
+/// What `get` prints for the data of `setting`
+fn display(setting: Setting, data: &str) -> anyhow::Result<String> {
+    Ok(match setting {
+        Setting::DefaultUid => parse_dword(data)?.to_string(),
+        Setting::Flags => format!("{:#x}", parse_dword(data)?),
+        Setting::Name => data.to_string(),
+    })
+}
+
+struct Registry<'a> {
+    reg: &'a Path,
+}
+
+impl Registry<'_> {
+    fn run(&self, args: &[&str]) -> anyhow::Result<String> {
+        let mut command = Command::new(self.reg);
+        command.args(args).stdin(Stdio::null());
+        use_live_socket(&mut command);
+        let output = command
+            .output()
+            .with_context(|| format!("When running {}", self.reg.display()))?;
+        if !output.status.success() {
+            bail!(
+                "reg.exe {} failed ({}): {}",
+                args.first().unwrap_or(&""),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn distro_key(&self, distro: &str) -> anyhow::Result<String> {
+        let output = self.run(&["query", LXSS_KEY, "/s", "/v", "DistributionName"])?;
+        find_distro_key(&output, distro)
+            .ok_or(anyhow!("WSL doesn't know a distro called {}", distro))
+    }
+
+    fn get(&self, key: &str, setting: Setting) -> anyhow::Result<String> {
+        let output = self.run(&["query", key, "/v", setting.value_name()])?;
+        output
+            .lines()
+            .filter_map(parse_value)
+            .find(|(name, _, _)| *name == setting.value_name())
+            .map(|(_, _, data)| data.to_string())
+            .ok_or(anyhow!("{} has no {}", key, setting.value_name()))
+    }
+
+    fn set(&self, key: &str, setting: Setting, data: &str) -> anyhow::Result<()> {
+        self.run(&[
+            "add",
+            key,
+            "/v",
+            setting.value_name(),
+            "/t",
+            setting.value_type(),
+            "/d",
+            data,
+            "/f",
+        ])?;
+        Ok(())
+    }
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    let distro = match args.distro {
+        Some(distro) => distro,
+        None => env::var("WSL_DISTRO_NAME")
+            .context("WSL_DISTRO_NAME is not set, pass the distro with --distro")?,
+    };
+    let registry = Registry { reg: &args.reg };
+    let key = registry.distro_key(&distro)?;
+
+    match args.command {
+        SettingsCommand::List => {
+            for setting in Setting::ALL {
+                let data = registry.get(&key, setting)?;
+                println!("{}={}", setting.name(), display(setting, &data)?);
+            }
+        }
+        SettingsCommand::Get { setting } => {
+            println!("{}", display(setting, &registry.get(&key, setting)?)?);
+        }
+        SettingsCommand::Set { setting, value } => {
+            let current = registry.get(&key, setting)?;
+            let data = registry_data(setting, &value, &current)?;
+            registry.set(&key, setting, &data)?;
+            eprintln!(
+                "Changed {} of {}, it applies after `wsl.exe --terminate {}`",
+                setting.name(),
+                distro,
+                distro
+            );
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUERY: &str = concat!(
+        "\r\n",
+        "HKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Lxss\\{1111}\r\n",
+        "    DistributionName    REG_SZ    Ubuntu\r\n",
+        "\r\n",
+        "HKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Lxss\\{2222}\r\n",
+        "    DistributionName    REG_SZ    NixOS\r\n",
+        "\r\n",
+        "End of search: 2 match(es) found.\r\n",
+    );
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(
+            parse_value("    DefaultUid    REG_DWORD    0x3e8\r"),
+            Some(("DefaultUid", "REG_DWORD", "0x3e8"))
+        );
+        assert_eq!(parse_value("HKEY_CURRENT_USER\\Software"), None);
+        assert_eq!(parse_dword("0x3e8").unwrap(), 1000);
+        assert!(parse_dword("1000").is_err());
+    }
+
+    #[test]
+    fn finds_distro_keys() {
+        assert_eq!(
+            find_distro_key(QUERY, "nixos").unwrap(),
+            "HKEY_CURRENT_USER\\Software\\Microsoft\\Windows\\CurrentVersion\\Lxss\\{2222}"
+        );
+        assert_eq!(find_distro_key(QUERY, "Debian"), None);
+    }
+
+    #[test]
+    fn checks_new_values() {
+        assert_eq!(
+            registry_data(Setting::DefaultUid, "1001", "0x3e8").unwrap(),
+            "1001"
+        );
+        assert_eq!(
+            registry_data(Setting::DefaultUid, "root", "0x3e8").unwrap(),
+            "0"
+        );
+        assert_eq!(registry_data(Setting::Flags, "0xd", "0xf").unwrap(), "13");
+        // Turning WSL 2 off this way would break the distro
+        assert!(registry_data(Setting::Flags, "0x7", "0xf").is_err());
+        assert!(registry_data(Setting::Name, "NixOS-unstable", "NixOS").is_ok());
+        assert!(registry_data(Setting::Name, "Nix OS", "NixOS").is_err());
+    }
+
+    #[test]
+    fn displays_values() {
+        assert_eq!(display(Setting::DefaultUid, "0x3e8").unwrap(), "1000");
+        assert_eq!(display(Setting::Flags, "0xf").unwrap(), "0xf");
+    }
+}
@@ -84,7 +84,7 @@ fn real_main() -> anyhow::Result<()> {
 
             // Load the environment from /etc/set-environment
             let output = Command::new(env!("NIXOS_WSL_SH"))
-                .args(&[
+                .args([
                     "-c",
                     &format!(". /etc/set-environment && {} -0", env!("NIXOS_WSL_ENV")),
                 ])
@@ -161,8 +161,9 @@ fn main() {
 
     if let Err(err) = real_main() {
         eprintln!("{:?}", &err);
-    error!("{:?}", &err);
+        error!("{:?}", &err);
     }
+}
 
 #[cfg(test)]
 mod tests {
@@ -197,4 +198,3 @@ mod tests {
         assert_eq!(env::var("SHELL").unwrap(), "/other");
     }
 }
-}
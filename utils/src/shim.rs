@@ -1,20 +1,77 @@
-use anyhow::{anyhow, Context};
+mod activation;
+mod check;
+mod config;
+mod effects;
+mod emergency;
+mod hugepages;
+mod logging;
+mod mounts;
+mod pipeline;
+mod retry;
+#[cfg(test)]
+mod test_util;
+
+use anyhow::{anyhow, bail, Context};
+use clap::{Parser, Subcommand};
 use nix::errno::Errno;
-use nix::mount::{mount, MsFlags};
+use nix::mount::MsFlags;
 use nix::sys::wait::{waitid, Id, WaitPidFlag};
 use nix::unistd::Pid;
 use std::env;
-use std::fs::{create_dir_all, metadata, remove_dir_all, remove_file, OpenOptions};
-use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::ffi::OsString;
+use std::fs::{read_to_string, symlink_metadata};
+use std::io::ErrorKind;
 use std::os::unix::process::CommandExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use nix::unistd::dup;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use activation::{terminate, wait_timeout, Output, Wait};
+use config::{
+    ActivationConfig, BootMenuConfig, DevShmConfig, FirstBootConfig, OnTimeout, ShimConfig,
+    CONFIG_PATH,
+};
+use effects::{create_dir_all, remove_dir_all, remove_file, set_mode};
+use hugepages::setup_hugepages;
+use logging::{open_log, LOG_DIR};
+use mounts::{Done, Mount, MountOp, MountPlan};
+use nixos_wsl_utils::build_info::{self, handle_json_version, BuildInfo};
+use nixos_wsl_utils::generations::{
+    boot_generation, fallback_generations, peek_boot_generation, Generation, PROFILES_DIR,
+    SYSTEM_PROFILE,
+};
+use nixos_wsl_utils::mountinfo::{MountEntry, MountInfo};
+use pipeline::{Pipeline, Step};
+use retry::{RetryPolicy, StepFailed};
+
+/// Options for a new /dev/shm, used when WSL didn't leave a /run/shm behind for us to move
+fn fallback_dev_shm_options(config: &DevShmConfig) -> String {
+    let mut options = format!(
+        "mode={:o},size={}",
+        config.mode.unwrap_or(0o1777),
+        config.size.as_deref().unwrap_or("50%")
+    );
+    if let Some(huge) = &config.huge {
+        options.push_str(&format!(",huge={}", huge));
+    }
+    options
+}
 
-fn unscrew_dev_shm() -> anyhow::Result<()> {
+/// WSL leaves /dev/shm as a symlink to /run/shm, or sometimes not at all
+fn dev_shm_needs_fixing(dev_shm: &Path) -> anyhow::Result<bool> {
+    // /run/shm might be missing, so don't follow the symlink
+    match symlink_metadata(dev_shm) {
+        Ok(metadata) => Ok(metadata.is_symlink()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e).with_context(|| format!("When checking {}", dev_shm.display())),
+    }
+}
+
+fn unscrew_dev_shm(config: &DevShmConfig) -> anyhow::Result<()> {
     log::trace!("Unscrewing /dev/shm...");
 
     let dev_shm = Path::new("/dev/shm");
+    let run_shm = Path::new("/run/shm");
 
     if dev_shm.is_symlink() {
         remove_file(dev_shm).context("When removing /dev/shm symlink")?;
@@ -23,117 +80,654 @@ fn unscrew_dev_shm() -> anyhow::Result<()> {
     }
 
     create_dir_all("/dev/shm").context("When creating new /dev/shm")?;
-    mount(
-        Some("/run/shm"),
-        "/dev/shm",
-        None::<&str>,
-        MsFlags::MS_MOVE,
-        None::<&str>,
-    )
-    .context("When relocating /dev/shm")?;
-    mount(
-        Some("/dev/shm"),
-        "/run/shm",
-        None::<&str>,
-        MsFlags::MS_BIND,
-        None::<&str>,
-    )
-    .context("When bind mounting /run/shm to /dev/shm")?;
 
+    let new_dev_shm = if MountInfo::read()?.is_mountpoint(run_shm) {
+        Mount {
+            source: Some(run_shm.into()),
+            target: dev_shm.into(),
+            fstype: None,
+            flags: MsFlags::MS_MOVE,
+            data: None,
+        }
+    } else {
+        log::warn!("/run/shm is not mounted, mounting a new tmpfs on /dev/shm instead");
+        create_dir_all(run_shm).context("When creating /run/shm")?;
+        Mount {
+            source: Some("tmpfs".into()),
+            target: dev_shm.into(),
+            fstype: Some("tmpfs"),
+            flags: MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            data: Some(fallback_dev_shm_options(config)),
+        }
+    };
+
+    MountPlan::new(vec![
+        MountOp {
+            name: "dev-shm",
+            after: &[],
+            mount: new_dev_shm,
+            done: Done::IfMounted,
+        },
+        MountOp {
+            name: "run-shm",
+            after: &["dev-shm"],
+            mount: Mount {
+                source: Some(dev_shm.into()),
+                target: run_shm.into(),
+                fstype: None,
+                flags: MsFlags::MS_BIND,
+                data: None,
+            },
+            done: Done::IfMounted,
+        },
+    ])?
+    .run()?;
     Ok(())
 }
 
+fn apply_dev_shm_config(config: &DevShmConfig) -> anyhow::Result<()> {
+    if let Some(options) = config.remount_options() {
+        log::trace!("Remounting /dev/shm with {}...", options);
+        let current = MountInfo::read()?
+            .find(Path::new("/dev/shm"))
+            .map_or(MsFlags::empty(), MountEntry::flags);
+        MountPlan::new(vec![MountOp {
+            name: "dev-shm-options",
+            after: &[],
+            mount: Mount {
+                source: None,
+                target: "/dev/shm".into(),
+                fstype: None,
+                flags: MsFlags::MS_REMOUNT | MsFlags::MS_NOSUID | MsFlags::MS_NODEV | current,
+                data: Some(options),
+            },
+            done: Done::Never,
+        }])?
+        .run()?;
+    }
+    if let Some(mode) = config.mode {
+        set_mode("/dev/shm", mode).context("When setting the mode of /dev/shm")?;
+    }
+    Ok(())
+}
+
+/// Same as --trace-mounts, since WSL doesn't let users pass arguments to init
+const TRACE_MOUNTS_CMDLINE: &str = "nixos-wsl.trace-mounts";
+/// Shows the boot menu, on the kernel command line or once via the marker file
+const BOOT_MENU_CMDLINE: &str = "nixos-wsl.boot-menu";
+const BOOT_MENU_MARKER: &str = "/etc/nixos-wsl/boot-menu";
+/// Written by nixos-wsl-first-boot once the distro is provisioned
+const FIRST_BOOT_MARKER: &str = "/var/lib/nixos-wsl/provisioned";
+/// Exists while systemd runs as init, see sd_booted(3)
+const SYSTEMD_RUNTIME_DIR: &str = "/run/systemd/system";
+
+/// Sets up what NixOS needs before systemd can start, then starts it.
+/// Arguments the shim doesn't know are passed through to systemd.
+#[derive(Parser, Debug, PartialEq)]
+#[command(name = "systemd-shim", version = build_info::VERSION)]
+struct ShimArgs {
+    #[command(subcommand)]
+    command: Option<ShimCommand>,
+
+    /// Dump the mount table before and after each boot step
+    #[arg(long)]
+    trace_mounts: bool,
+
+    /// Print the boot steps and exit
+    #[arg(long)]
+    list_steps: bool,
+
+    /// Only run the given steps (comma separated or repeated), then exit instead of starting systemd
+    #[arg(long, value_name = "STEP", value_delimiter = ',')]
+    only: Vec<String>,
+
+    /// Print every mount and exec instead of doing it
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Run the boot steps, then exit instead of starting systemd.
+    /// This is the default if systemd is already running, e.g. when WSL started it as init itself.
+    #[arg(long)]
+    fixups_only: bool,
+
+    /// Exec PROGRAM with the remaining arguments after the boot steps, instead of systemd
+    #[arg(long, value_name = "PROGRAM")]
+    exec: Option<PathBuf>,
+
+    /// Passed through to systemd, or to the --exec program
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, hide = true)]
+    systemd_args: Vec<OsString>,
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum ShimCommand {
+    /// Check that everything the boot depends on is in place
+    Check {
+        /// One JSON object per line instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the versions of the shim, NixOS-WSL, NixOS, the kernel and WSL, for bug reports
+    Info {
+        /// One JSON object instead of text
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+fn parse_args(args: impl IntoIterator<Item = OsString>) -> Result<ShimArgs, clap::Error> {
+    let mut parsed =
+        ShimArgs::try_parse_from(std::iter::once(OsString::from("systemd-shim")).chain(args))?;
+    parsed.only.retain(|step| !step.is_empty());
+    Ok(parsed)
+}
+
+/// Only the boot WSL starts logs to kmsg and the shim log, not e.g. `info`, `check` or a dry run run by hand
+fn is_boot(args: &ShimArgs, pid: i32) -> bool {
+    pid == 1 && args.command.is_none() && !args.dry_run && !args.list_steps
+}
+
+/// Whether another process already runs systemd as init. Starting a second one would fight over the system.
+fn systemd_is_running(pid: i32, runtime_dir: &Path) -> bool {
+    pid != 1 && runtime_dir.is_dir()
+}
+
+fn cmdline_has_flag(cmdline: &str, flag: &str) -> bool {
+    cmdline.split_whitespace().any(|word| word == flag)
+}
+
+/// Removes the marker file, returning whether it was there
+fn take_marker(path: &Path) -> bool {
+    match remove_file(path) {
+        Ok(()) => true,
+        Err(e) if e.kind() == ErrorKind::NotFound => false,
+        Err(e) => {
+            log::warn!("Could not remove {}: {}", path.display(), e);
+            true
+        }
+    }
+}
+
+/// Lets the user pick a generation on the console. Returns None if the default was chosen.
+fn run_boot_menu(config: &BootMenuConfig) -> anyhow::Result<Option<Generation>> {
+    let path = config.path.as_ref().ok_or(anyhow!(
+        "the boot menu is not enabled (wsl.shim.bootMenu.enable)"
+    ))?;
+    let console = emergency::open_console()?;
+
+    let mut command = Command::new(path);
+    command.arg("--print");
+    if let Some(timeout) = config.timeout {
+        command.arg(format!("--timeout={}", timeout.as_secs()));
+    }
+    let output = command
+        .stdin(console.try_clone().context("When duplicating console fd")?)
+        .stderr(console)
+        .output()
+        .with_context(|| format!("When running {}", path.display()))?;
+    if !output.status.success() {
+        bail!("{} failed with {}", path.display(), output.status);
+    }
+
+    let chosen = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if chosen.is_empty() {
+        return Ok(None);
+    }
+    let generation = Generation::new(chosen);
+    if !generation.is_bootable() {
+        bail!("{} is not a NixOS system", generation.path.display());
+    }
+    Ok(Some(generation))
+}
+
+/// Provisions the distro on its first boot, see nixos-wsl-first-boot
+fn run_first_boot(config: &FirstBootConfig, marker: &Path) -> anyhow::Result<()> {
+    let path = match &config.path {
+        Some(path) => path,
+        None => {
+            log::trace!("First boot provisioning is not enabled, skipping...");
+            return Ok(());
+        }
+    };
+    if marker.exists() {
+        log::trace!("{} exists, already provisioned", marker.display());
+        return Ok(());
+    }
+    if effects::dry_run(|| format!("run {}", path.display())) {
+        return Ok(());
+    }
+
+    let output = Command::new(path)
+        .arg(format!("--marker={}", marker.display()))
+        .env("LANG", "C.UTF-8")
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("When running {}", path.display()))?;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        log::info!("first-boot: {}", line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        log::warn!("first-boot: {}", line);
+    }
+    if !output.status.success() {
+        bail!("{} failed with {}", path.display(), output.status);
+    }
+    Ok(())
+}
+
+fn log_mountinfo(step: &str, when: &str) {
+    match read_to_string("/proc/self/mountinfo") {
+        Ok(table) => {
+            log::info!("Mount table {} {}:", when, step);
+            // One line per entry, kmsg truncates long records
+            for line in table.lines() {
+                log::info!("[{}/{}] {}", step, when, line);
+            }
+        }
+        Err(e) => log::warn!("Could not read /proc/self/mountinfo: {}", e),
+    }
+}
+
+// Early mounts can fail while WSL's own init is still busy, so they get a few tries.
+// A broken /dev/shm or a writable store is bad, but still a better outcome than not booting at all.
+const HUGEPAGES_POLICY: RetryPolicy = RetryPolicy {
+    attempts: 1,
+    backoff: Duration::ZERO,
+    fatal: false,
+};
+const DEV_SHM_POLICY: RetryPolicy = RetryPolicy {
+    attempts: 5,
+    backoff: Duration::from_millis(50),
+    fatal: false,
+};
+const ROOT_SHARED_POLICY: RetryPolicy = RetryPolicy {
+    attempts: 5,
+    backoff: Duration::from_millis(50),
+    fatal: true,
+};
+const STORE_RO_POLICY: RetryPolicy = RetryPolicy {
+    attempts: 5,
+    backoff: Duration::from_millis(50),
+    fatal: false,
+};
+// Activation is not idempotent enough to just run it again
+const ACTIVATION_POLICY: RetryPolicy = RetryPolicy::FATAL_ONCE;
+// Provisioning picks up where it stopped on the next boot, which is a better retry than doing it again right away
+const FIRST_BOOT_POLICY: RetryPolicy = RetryPolicy {
+    attempts: 1,
+    backoff: Duration::ZERO,
+    fatal: false,
+};
+
+fn boot_steps<'a>(config: &'a ShimConfig, generation: &'a Generation) -> Vec<Step<'a>> {
+    let mut steps = vec![
+        // Do this first, the longer we wait the more fragmented memory gets
+        Step {
+            name: "hugepages",
+            description: "Reserve hugepages and mount hugetlbfs",
+            after: &[],
+            policy: HUGEPAGES_POLICY,
+            run: Box::new(|| setup_hugepages(&config.hugepages)),
+        },
+        Step {
+            name: "dev-shm",
+            description: "Turn the /dev/shm symlink into a real tmpfs",
+            after: &[],
+            policy: DEV_SHM_POLICY,
+            run: Box::new(|| {
+                if dev_shm_needs_fixing(Path::new("/dev/shm"))? {
+                    unscrew_dev_shm(&config.dev_shm)?;
+                } else {
+                    log::trace!("/dev/shm is not a symlink, leaving as-is...");
+                };
+                apply_dev_shm_config(&config.dev_shm)
+            }),
+        },
+        Step {
+            name: "root-shared",
+            description: "Remount / with shared propagation",
+            after: &[],
+            policy: ROOT_SHARED_POLICY,
+            run: Box::new(|| {
+                log::trace!("Remounting / shared...");
+                remount_root_shared()
+            }),
+        },
+        // The store bind mount should be created below a shared / so it propagates into new namespaces
+        Step {
+            name: "store-ro",
+            description: "Bind mount /nix/store read-only",
+            after: &["root-shared"],
+            policy: STORE_RO_POLICY,
+            run: Box::new(|| {
+                log::trace!("Remounting /nix/store read-only...");
+                remount_nix_store_readonly()
+            }),
+        },
+        Step {
+            name: "activation",
+            description: "Run the NixOS activation script",
+            after: &[],
+            policy: ACTIVATION_POLICY,
+            run: Box::new(|| run_activation(generation, &config.activation)),
+        },
+        // Needs the users, groups and /run/current-system activation sets up
+        Step {
+            name: "first-boot",
+            description: "Provision a freshly imported distro",
+            after: &["activation"],
+            policy: FIRST_BOOT_POLICY,
+            run: Box::new(|| run_first_boot(&config.first_boot, Path::new(FIRST_BOOT_MARKER))),
+        },
+    ];
+    for step in &mut steps {
+        if let Some(on_failure) = config.step_policies.get(step.name) {
+            step.policy = on_failure.apply(step.policy);
+        }
+    }
+    steps
+}
+
+/// Runs the checks of `systemd-shim check` against the generation the next boot would use
+fn run_check(json: bool) -> anyhow::Result<()> {
+    let (config, config_error) = match ShimConfig::load(Path::new(CONFIG_PATH)) {
+        Ok(config) => (config, None),
+        Err(e) => (ShimConfig::default(), Some(e)),
+    };
+    let generation = peek_boot_generation();
+    let pipeline =
+        Pipeline::new(boot_steps(&config, &generation)).context("When setting up boot steps")?;
+    let diagnostics = check::diagnose(&config, config_error.as_ref(), &generation, &pipeline);
+    check::report(&diagnostics, json)
+}
+
 fn real_main() -> anyhow::Result<()> {
-    if metadata("/dev/shm")
-        .context("When checking /dev/shm")?
-        .is_symlink()
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let mut args = env::args_os();
+    let arg0 = args.next().expect("arg0 missing");
+    let mut shim_args = match parse_args(args) {
+        Ok(shim_args) => shim_args,
+        // --help and --version
+        Err(e) if !e.use_stderr() => {
+            let _ = e.print();
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if let Err(e) = logging::init(is_boot(&shim_args, Pid::this().as_raw())) {
+        eprintln!("Failed to set up logger: {:?}", e);
+    }
+    match shim_args.command {
+        Some(ShimCommand::Check { json }) => return run_check(json),
+        Some(ShimCommand::Info { json }) => {
+            let info = BuildInfo::detect(env!("CARGO_BIN_NAME"));
+            if json {
+                println!("{}", info.format_json());
+            } else {
+                print!("{}", info.format_text());
+            }
+            return Ok(());
+        }
+        None => {}
+    }
+    if let Ok(cmdline) = read_to_string("/proc/cmdline") {
+        shim_args.trace_mounts |= cmdline_has_flag(&cmdline, TRACE_MOUNTS_CMDLINE);
+    }
+    effects::set_dry_run(shim_args.dry_run);
+    if !shim_args.fixups_only
+        && systemd_is_running(Pid::this().as_raw(), Path::new(SYSTEMD_RUNTIME_DIR))
     {
-        unscrew_dev_shm()?;
+        log::warn!("systemd is already running, only running the boot steps...");
+        shim_args.fixups_only = true;
+    }
+    let booting = !shim_args.list_steps && shim_args.only.is_empty() && !shim_args.fixups_only;
+
+    // A broken config shouldn't keep the distro from booting
+    let config = ShimConfig::load(Path::new(CONFIG_PATH)).unwrap_or_else(|e| {
+        log::error!("Using the default shim config: {:?}", e);
+        ShimConfig::default()
+    });
+
+    let mut show_menu = false;
+    if booting && !shim_args.dry_run {
+        show_menu = take_marker(Path::new(BOOT_MENU_MARKER));
+        if let Ok(cmdline) = read_to_string("/proc/cmdline") {
+            show_menu |= cmdline_has_flag(&cmdline, BOOT_MENU_CMDLINE);
+        }
+    }
+    let chosen = if show_menu {
+        run_boot_menu(&config.boot_menu).unwrap_or_else(|e| {
+            log::error!("Could not show the boot menu: {:?}", e);
+            None
+        })
     } else {
-        log::trace!("/dev/shm is not a symlink, leaving as-is...");
+        None
+    };
+
+    // Running steps by hand or a dry run must not use up a staged generation
+    let mut generation = match chosen {
+        Some(generation) => generation,
+        None if booting && shim_args.dry_run => peek_boot_generation(),
+        None if booting => boot_generation(),
+        None => Generation::new(SYSTEM_PROFILE),
     };
 
-    log::trace!("Remounting / shared...");
-    remount_root_shared()?;
+    let mut pipeline =
+        Pipeline::new(boot_steps(&config, &generation)).context("When setting up boot steps")?;
 
-    log::trace!("Remounting /nix/store read-only...");
-    remount_nix_store_readonly()?;
+    if shim_args.list_steps {
+        print!("{}", pipeline.describe());
+        return Ok(());
+    }
 
-    log::trace!("Running activation script...");
+    if let Some(step) = shim_args.only.iter().find(|s| !pipeline.has_step(s)) {
+        bail!("Unknown boot step: {}", step);
+    }
+    for step in config
+        .disabled_steps
+        .iter()
+        .chain(config.step_policies.keys())
+        .filter(|s| !pipeline.has_step(s))
+    {
+        log::warn!("Ignoring unknown boot step {} in {}", step, CONFIG_PATH);
+    }
 
-    let kmsg_fd = OpenOptions::new()
-        .write(true)
-        .open("/dev/kmsg")
-        .context("When opening /dev/kmsg")?
-        .into_raw_fd();
-    // Duplicate the fd so stdout and stderr don't share and double-close the same descriptor
-    let kmsg_fd_err = dup(kmsg_fd).context("When duplicating /dev/kmsg fd")?;
+    log::trace!("Booting {}...", generation.path.display());
+    let result = pipeline.run(
+        |step| {
+            if shim_args.only.is_empty() {
+                !config.disabled_steps.contains(step)
+            } else {
+                shim_args.only.iter().any(|s| s == step)
+            }
+        },
+        shim_args.trace_mounts,
+    );
+    drop(pipeline);
+
+    if let Err(e) = result {
+        let activation_failed = matches!(
+            e.downcast_ref::<StepFailed>(),
+            Some(StepFailed(step)) if step == "activation"
+        );
+        if !booting || !activation_failed {
+            return Err(e);
+        }
+        log::error!("{:?}", e);
+        generation = fall_back(&generation, &config.activation)?;
+    }
+
+    if let Some(program) = shim_args.exec {
+        log::trace!("Spawning {}...", program.display());
+        let mut command = Command::new(program);
+        command.args(shim_args.systemd_args);
+        return exec(command);
+    }
+    if !booting {
+        return Ok(());
+    }
+
+    let systemd = config
+        .systemd
+        .path
+        .clone()
+        .unwrap_or_else(|| generation.systemd());
+    log::trace!("Spawning real systemd ({})...", systemd.display());
+
+    let mut command = Command::new(systemd);
+    command
+        .arg0(arg0)
+        .arg("--log-target=kmsg") // log to dmesg
+        .args(&config.systemd.extra_args)
+        .args(shim_args.systemd_args);
+    exec(command)
+}
+
+fn exec(mut command: Command) -> anyhow::Result<()> {
+    if effects::dry_run(|| format!("exec {:?}", command)) {
+        return Ok(());
+    }
+    // if things go right, we will never return from here
+    Err(command.exec().into())
+}
+
+/// How long a timed out activation script gets to exit after SIGTERM, before it is killed
+const ACTIVATION_TERM_GRACE: Duration = Duration::from_secs(10);
+/// How long to wait for the rest of the activation output after the script exited
+const ACTIVATION_OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+fn run_activation(generation: &Generation, config: &ActivationConfig) -> anyhow::Result<()> {
+    log::trace!("Running activation script...");
+    if effects::dry_run(|| format!("run {}", generation.activate().display())) {
+        return Ok(());
+    }
 
-    let child = Command::new("/nix/var/nix/profiles/system/activate")
+    // Piped through the shim instead of going to /dev/kmsg directly, so the output ends up in every log target
+    let mut child = Command::new(generation.activate())
         .env("LANG", "C.UTF-8")
-        // SAFETY: we just opened this
-        .stdout(unsafe { Stdio::from_raw_fd(kmsg_fd) })
-        .stderr(unsafe { Stdio::from_raw_fd(kmsg_fd_err) })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
         .context("When activating")?;
 
     let pid = Pid::from_raw(child.id() as i32);
+    let log_file = if config.keep_logs > 0 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        open_log(Path::new(LOG_DIR), "activation", config.keep_logs, now)
+            .map_err(|e| log::warn!("Not saving activation output: {:?}", e))
+            .ok()
+    } else {
+        None
+    };
+    let output = Output::capture(&mut child, log_file);
 
-    // If the child catches SIGCHLD, `waitid` will wait for it to exit, then return ECHILD.
-    // Why? Because POSIX is terrible.
-    match child.wait() {
-        Ok(status) => {
-            check_activation_exit(status.code())?;
+    let result = match wait_timeout(&mut child, config.timeout) {
+        Ok(Wait::Exited(status)) => check_activation_exit(status.code()),
+        Ok(Wait::TimedOut) => {
+            let timeout = config.timeout.unwrap_or_default();
+            log::error!(
+                "Activation did not finish within {:?}, stopping it...",
+                timeout
+            );
+            if let Err(e) = terminate(&mut child, ACTIVATION_TERM_GRACE) {
+                log::error!("Could not stop activation: {:?}", e);
+            }
+            output.finish(ACTIVATION_OUTPUT_GRACE);
+            log::error!("Last lines of activation output:");
+            for line in output.tail() {
+                log::error!("  {}", line);
+            }
+            return match config.on_timeout {
+                OnTimeout::Continue => {
+                    log::warn!("Starting systemd anyway, the system might be half activated");
+                    Ok(())
+                }
+                OnTimeout::Recover => Err(anyhow!("Activation timed out after {:?}", timeout)),
+            };
         }
+        // If the child catches SIGCHLD, `waitid` will wait for it to exit, then return ECHILD.
+        // Why? Because POSIX is terrible.
         Err(_) => {
-            let result = waitid(Id::Pid(pid), WaitPidFlag::WEXITED);
-            interpret_waitid_result(result)?;
+            let result = waitid(Id::Pid(pid), WaitPidFlag::WEXITED).map(|_| ());
+            interpret_waitid_result(result)
         }
-    }
+    };
+    output.finish(ACTIVATION_OUTPUT_GRACE);
+    result
+}
 
-    log::trace!("Spawning real systemd...");
+/// Older generations are only tried a few times, so a store that is broken for every generation
+/// doesn't keep the distro activating for minutes before giving up
+const MAX_FALLBACKS: usize = 3;
 
-    // if things go right, we will never return from here
-    Err(
-        Command::new("/nix/var/nix/profiles/system/systemd/lib/systemd/systemd")
-            .arg0(env::args_os().next().expect("arg0 missing"))
-            .arg("--log-target=kmsg") // log to dmesg
-            .args(env::args_os().skip(1))
-            .exec()
-            .into(),
+/// Activates the newest generation before `failed` that still activates, and returns it
+fn fall_back(failed: &Generation, config: &ActivationConfig) -> anyhow::Result<Generation> {
+    for generation in fallback_generations(Path::new(PROFILES_DIR), failed)
+        .into_iter()
+        .take(MAX_FALLBACKS)
+    {
+        log::warn!("Falling back to {}...", generation.path.display());
+        match run_activation(&generation, config) {
+            Ok(()) => return Ok(generation),
+            Err(e) => log::error!("Activating {} failed: {:?}", generation.path.display(), e),
+        }
+    }
+    bail!(
+        "Activating {} failed and there is no older generation to fall back to",
+        failed.path.display()
     )
 }
 
 fn remount_root_shared() -> anyhow::Result<()> {
-    mount(
-        None::<&str>,
-        "/",
-        None::<&str>,
-        MsFlags::MS_REC | MsFlags::MS_SHARED,
-        None::<&str>,
-    )
-    .context("When remounting /")?;
+    MountPlan::new(vec![MountOp {
+        name: "root-shared",
+        after: &[],
+        mount: Mount {
+            source: None,
+            target: "/".into(),
+            fstype: None,
+            flags: MsFlags::MS_REC | MsFlags::MS_SHARED,
+            data: None,
+        },
+        done: Done::IfShared,
+    }])?
+    .run()?;
     Ok(())
 }
 
 fn remount_nix_store_readonly() -> anyhow::Result<()> {
-    mount(
-        Some("/nix/store"),
-        "/nix/store",
-        None::<&str>,
-        MsFlags::MS_BIND,
-        None::<&str>,
-    )
-    .context("When bind mounting /nix/store")?;
-
-    mount(
-        Some("/nix/store"),
-        "/nix/store",
-        None::<&str>,
-        MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
-        None::<&str>,
-    )
-    .context("When remounting /nix/store read-only")?;
+    MountPlan::new(vec![
+        // A bind mount of its own, so the read-only flag doesn't apply to all of /.
+        // If the store already is a mount point, e.g. after a restarted boot, that one is remounted instead.
+        MountOp {
+            name: "store-bind",
+            after: &[],
+            mount: Mount {
+                source: Some("/nix/store".into()),
+                target: "/nix/store".into(),
+                fstype: None,
+                flags: MsFlags::MS_BIND,
+                data: None,
+            },
+            done: Done::IfMounted,
+        },
+        MountOp {
+            name: "store-ro",
+            after: &["store-bind"],
+            mount: Mount {
+                source: Some("/nix/store".into()),
+                target: "/nix/store".into(),
+                fstype: None,
+                flags: MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                data: None,
+            },
+            done: Done::IfReadOnly,
+        },
+    ])?
+    .run()?;
     Ok(())
 }
 
@@ -155,6 +749,8 @@ fn check_activation_exit(code: Option<i32>) -> anyhow::Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::scratch_dir;
+    use logging::LogSettings;
     use nix::errno::Errno;
 
     #[test]
@@ -186,16 +782,226 @@ mod tests {
     fn activation_exit_none_is_err() {
         assert!(check_activation_exit(None).is_err());
     }
+
+    #[test]
+    fn fallback_dev_shm_uses_config() {
+        assert_eq!(
+            fallback_dev_shm_options(&DevShmConfig::default()),
+            "mode=1777,size=50%"
+        );
+        let config = DevShmConfig {
+            size: Some("8G".to_string()),
+            mode: Some(0o1770),
+            huge: Some("advise".to_string()),
+        };
+        assert_eq!(
+            fallback_dev_shm_options(&config),
+            "mode=1770,size=8G,huge=advise"
+        );
+    }
+
+    #[test]
+    fn fixes_missing_or_symlinked_dev_shm() {
+        let dir = scratch_dir("dev-shm");
+        assert!(dev_shm_needs_fixing(&dir.join("shm")).unwrap());
+        std::os::unix::fs::symlink(dir.join("run-shm"), dir.join("shm")).unwrap();
+        assert!(dev_shm_needs_fixing(&dir.join("shm")).unwrap());
+        assert!(!dev_shm_needs_fixing(&dir).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trace_mounts_arg_is_not_passed_to_systemd() {
+        let args =
+            parse_args(["--trace-mounts", "--unit=multi-user.target"].map(OsString::from)).unwrap();
+        assert!(args.trace_mounts);
+        assert_eq!(
+            args.systemd_args,
+            vec![OsString::from("--unit=multi-user.target")]
+        );
+    }
+
+    #[test]
+    fn args_pass_through_by_default() {
+        let args = parse_args(["--system"].map(OsString::from)).unwrap();
+        assert!(!args.trace_mounts);
+        assert_eq!(args.systemd_args, vec![OsString::from("--system")]);
+    }
+
+    #[test]
+    fn parses_step_selection() {
+        let args = parse_args(
+            [
+                "--only",
+                "dev-shm,store-ro",
+                "--only=activation",
+                "--list-steps",
+            ]
+            .map(OsString::from),
+        )
+        .unwrap();
+        assert!(args.list_steps);
+        assert_eq!(args.only, vec!["dev-shm", "store-ro", "activation"]);
+        assert!(args.systemd_args.is_empty());
+    }
+
+    #[test]
+    fn only_requires_a_value() {
+        assert!(parse_args(["--only"].map(OsString::from)).is_err());
+    }
+
+    #[test]
+    fn unknown_words_pass_through() {
+        let args = parse_args(["single", "--dry-run"].map(OsString::from)).unwrap();
+        assert_eq!(args.command, None);
+        assert_eq!(
+            args.systemd_args,
+            vec![OsString::from("single"), OsString::from("--dry-run")]
+        );
+    }
+
+    #[test]
+    fn exec_takes_the_remaining_args() {
+        let args = parse_args(
+            ["--fixups-only", "--exec", "/bin/sh", "-l", "--only=x"].map(OsString::from),
+        )
+        .unwrap();
+        assert!(args.fixups_only);
+        assert_eq!(args.exec, Some(PathBuf::from("/bin/sh")));
+        assert_eq!(
+            args.systemd_args,
+            vec![OsString::from("-l"), OsString::from("--only=x")]
+        );
+        assert!(args.only.is_empty());
+    }
+
+    #[test]
+    fn detects_running_systemd() {
+        let dir = std::env::temp_dir();
+        assert!(systemd_is_running(1234, &dir));
+        // We are init, so whatever is there is left over
+        assert!(!systemd_is_running(1, &dir));
+        assert!(!systemd_is_running(1234, &dir.join("nixos-wsl-no-systemd")));
+    }
+
+    #[test]
+    fn parses_check_and_dry_run() {
+        let args = parse_args(["--dry-run"].map(OsString::from)).unwrap();
+        assert!(args.dry_run);
+        let args = parse_args(["check", "--json"].map(OsString::from)).unwrap();
+        assert_eq!(args.command, Some(ShimCommand::Check { json: true }));
+        let args = parse_args(["info"].map(OsString::from)).unwrap();
+        assert_eq!(args.command, Some(ShimCommand::Info { json: false }));
+    }
+
+    #[test]
+    fn only_boots_log_to_the_shim_log() {
+        let boot =
+            |args: &[&str]| is_boot(&parse_args(args.iter().map(OsString::from)).unwrap(), 1);
+        assert!(boot(&[]));
+        assert!(boot(&["--unit=multi-user.target"]));
+        assert!(!boot(&["--dry-run"]));
+        assert!(!boot(&["info"]));
+        assert!(!boot(&["check"]));
+        assert!(!boot(&["--list-steps"]));
+        assert!(!is_boot(&parse_args([]).unwrap(), 1234));
+    }
+
+    #[test]
+    fn dry_run_and_info_leave_the_log_dir_alone() {
+        let dir = scratch_dir("shim-logs");
+        let open = |args: &[&str]| {
+            let args = parse_args(args.iter().map(OsString::from)).unwrap();
+            let settings = LogSettings::default().for_run(is_boot(&args, 1));
+            logging::open_outputs(&settings, &dir);
+        };
+        open(&["--dry-run"]);
+        open(&["info"]);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        open(&[]);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn boot_steps_are_valid() {
+        let config = ShimConfig::default();
+        let generation = Generation::new(SYSTEM_PROFILE);
+        let pipeline = Pipeline::new(boot_steps(&config, &generation)).unwrap();
+        for step in [
+            "hugepages",
+            "dev-shm",
+            "root-shared",
+            "store-ro",
+            "activation",
+            "first-boot",
+        ] {
+            assert!(pipeline.has_step(step));
+        }
+    }
+
+    #[test]
+    fn boot_steps_use_configured_policies() {
+        let mut config = ShimConfig::default();
+        config
+            .step_policies
+            .insert("root-shared".to_string(), config::OnFailure::Skip);
+        let generation = Generation::new(SYSTEM_PROFILE);
+        let steps = boot_steps(&config, &generation);
+        let policy = |name: &str| steps.iter().find(|s| s.name == name).unwrap().policy;
+        assert!(!policy("root-shared").fatal);
+        assert_eq!(policy("store-ro"), STORE_RO_POLICY);
+    }
+
+    #[test]
+    fn marker_is_taken_once() {
+        let marker = std::env::temp_dir().join(format!("nixos-wsl-marker-{}", std::process::id()));
+        std::fs::write(&marker, "").unwrap();
+        assert!(take_marker(&marker));
+        assert!(!take_marker(&marker));
+    }
+
+    #[test]
+    fn first_boot_runs_until_provisioned() {
+        let marker =
+            std::env::temp_dir().join(format!("nixos-wsl-provisioned-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        // Disabled
+        run_first_boot(&FirstBootConfig::default(), &marker).unwrap();
+        let config = FirstBootConfig {
+            path: Some(PathBuf::from("/nonexistent/nixos-wsl-first-boot")),
+        };
+        assert!(run_first_boot(&config, &marker).is_err());
+        std::fs::write(&marker, "").unwrap();
+        run_first_boot(&config, &marker).unwrap();
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    fn cmdline_flag_matches_whole_words() {
+        assert!(cmdline_has_flag(
+            "quiet nixos-wsl.trace-mounts\n",
+            "nixos-wsl.trace-mounts"
+        ));
+        assert!(!cmdline_has_flag(
+            "nixos-wsl.trace-mounts=0",
+            "nixos-wsl.trace-mounts"
+        ));
+    }
 }
 
 #[cfg(all(test, target_os = "linux"))]
 mod integration {
     use super::*;
 
-    fn is_root() -> bool { nix::unistd::geteuid().is_root() }
+    fn is_root() -> bool {
+        nix::unistd::geteuid().is_root()
+    }
     fn is_wsl() -> bool {
-        std::env::var("WSL_INTEROP").is_ok() ||
-        std::fs::read_to_string("/proc/sys/kernel/osrelease").map(|s| s.contains("microsoft")).unwrap_or(false)
+        std::env::var("WSL_INTEROP").is_ok()
+            || std::fs::read_to_string("/proc/sys/kernel/osrelease")
+                .map(|s| s.contains("microsoft"))
+                .unwrap_or(false)
     }
 
     #[test]
@@ -210,8 +1016,13 @@ mod integration {
 
 fn main() {
     env::set_var("RUST_BACKTRACE", "1");
-    kernlog::init().expect("Failed to set up logger...");
     if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
         log::error!("Error: {:?}", e);
+        // Returning would leave WSL without an init, so give the user a way to repair the system instead
+        if Pid::this().as_raw() == 1 {
+            emergency::run(&e);
+        }
+        std::process::exit(1);
     }
 }
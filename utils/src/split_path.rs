@@ -1,20 +1,92 @@
 use std::{
     env,
     ffi::{OsStr, OsString},
+    fs::read_to_string,
     io::{self, Write},
     os::unix::prelude::{OsStrExt, OsStringExt},
     path::{Path, PathBuf},
 };
 
+use anyhow::{bail, Context};
 use clap::Parser;
 
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+
 #[derive(Parser, Debug)]
+#[command(name = "split-path", version = build_info::VERSION)]
 struct Args {
     #[arg(long)]
     automount_root: PathBuf,
 
     #[arg(long)]
     include_interop: bool,
+
+    /// Which Windows PATH entries to include, see wsl.interop.pathFilter
+    #[arg(long)]
+    filter: Option<PathBuf>,
+}
+
+/// Patterns for Windows PATH entries, where `*` matches anything but `/`.
+/// An entry is kept if it matches no deny pattern, and an allow pattern unless there are none.
+#[derive(Debug, Default, PartialEq)]
+struct PathFilter {
+    allow: Vec<Vec<u8>>,
+    deny: Vec<Vec<u8>>,
+}
+
+impl PathFilter {
+    /// Parses `allow PATTERN` and `deny PATTERN` lines, `#` starts a comment line
+    fn parse(contents: &str) -> anyhow::Result<PathFilter> {
+        let mut filter = PathFilter::default();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (kind, pattern) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let pattern = normalize(pattern.trim().as_bytes());
+            if pattern.is_empty() {
+                bail!("line {}: missing pattern", number + 1);
+            }
+            match kind {
+                "allow" => filter.allow.push(pattern),
+                "deny" => filter.deny.push(pattern),
+                _ => bail!(
+                    "line {}: expected allow or deny, not {:?}",
+                    number + 1,
+                    kind
+                ),
+            }
+        }
+        Ok(filter)
+    }
+
+    fn keeps(&self, path: &Path) -> bool {
+        let path = normalize(path.as_os_str().as_bytes());
+        let matches = |pattern: &Vec<u8>| wildcard_match(pattern, &path);
+        !self.deny.iter().any(matches) && (self.allow.is_empty() || self.allow.iter().any(matches))
+    }
+}
+
+/// Windows paths don't care about case or trailing slashes
+fn normalize(path: &[u8]) -> Vec<u8> {
+    let mut path = path.to_ascii_lowercase();
+    while path.len() > 1 && path.ends_with(b"/") {
+        path.pop();
+    }
+    path
+}
+
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => {
+            // Try every length up to the next slash
+            let limit = text.iter().position(|&b| b == b'/').unwrap_or(text.len());
+            (0..=limit).any(|skip| wildcard_match(rest, &text[skip..]))
+        }
+        Some((&byte, rest)) => text.first() == Some(&byte) && wildcard_match(rest, &text[1..]),
+    }
 }
 
 const SINGLE_QUOTE: u8 = b'\'';
@@ -54,20 +126,32 @@ fn build_export(var: &str, paths: &[PathBuf]) -> OsString {
     result
 }
 
-fn do_split_paths(path: &OsStr, automount_root: &Path, include_interop: bool) -> OsString {
-    let mut native = vec![];
-    let mut interop = vec![];
+fn do_split_paths(
+    path: &OsStr,
+    automount_root: &Path,
+    include_interop: bool,
+    filter: &PathFilter,
+) -> OsString {
+    let mut native: Vec<PathBuf> = vec![];
+    let mut interop: Vec<PathBuf> = vec![];
 
     for part in env::split_paths(&path) {
         if part.starts_with(automount_root) {
-            interop.push(part);
-        } else {
+            let key = normalize(part.as_os_str().as_bytes());
+            if !interop
+                .iter()
+                .any(|seen| normalize(seen.as_os_str().as_bytes()) == key)
+            {
+                interop.push(part);
+            }
+        } else if !native.contains(&part) {
             native.push(part);
         }
     }
 
+    // WSLPATH keeps everything, so filtered tools can still be found
     if include_interop {
-        native.extend(interop.clone());
+        native.extend(interop.iter().filter(|part| filter.keeps(part)).cloned());
     };
 
     let mut result = OsString::new();
@@ -77,13 +161,23 @@ fn do_split_paths(path: &OsStr, automount_root: &Path, include_interop: bool) ->
 }
 
 fn main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
     let args = Args::parse();
 
     let path = env::var_os("PATH").expect("PATH is not set, aborting");
+    let filter = match &args.filter {
+        Some(file) => {
+            let contents =
+                read_to_string(file).with_context(|| format!("When reading {}", file.display()))?;
+            PathFilter::parse(&contents)
+                .with_context(|| format!("When parsing {}", file.display()))?
+        }
+        None => PathFilter::default(),
+    };
 
-    io::stdout()
-        .lock()
-        .write_all(do_split_paths(&path, &args.automount_root, args.include_interop).as_bytes())?;
+    io::stdout().lock().write_all(
+        do_split_paths(&path, &args.automount_root, args.include_interop, &filter).as_bytes(),
+    )?;
 
     Ok(())
 }
@@ -92,7 +186,7 @@ fn main() -> anyhow::Result<()> {
 mod tests {
     use std::{ffi::OsString, path::Path};
 
-    use crate::do_split_paths;
+    use crate::{do_split_paths, PathFilter};
 
     #[test]
     fn simple() {
@@ -100,7 +194,8 @@ mod tests {
             do_split_paths(
                 &OsString::from("/good/foo:/bad/foo"),
                 Path::new("/bad"),
-                false
+                false,
+                &PathFilter::default()
             ),
             OsString::from("export PATH='/good/foo'\nexport WSLPATH='/bad/foo'\n")
         );
@@ -109,7 +204,12 @@ mod tests {
     #[test]
     fn exactly_one() {
         assert_eq!(
-            do_split_paths(&OsString::from("/good/foo"), Path::new("/bad"), true),
+            do_split_paths(
+                &OsString::from("/good/foo"),
+                Path::new("/bad"),
+                true,
+                &PathFilter::default()
+            ),
             OsString::from("export PATH='/good/foo'\nexport WSLPATH=''\n")
         );
     }
@@ -120,7 +220,8 @@ mod tests {
             do_split_paths(
                 &OsString::from("/good/foo:/bad/foo"),
                 Path::new("/bad"),
-                true
+                true,
+                &PathFilter::default()
             ),
             OsString::from("export PATH='/good/foo:/bad/foo'\nexport WSLPATH='/bad/foo'\n")
         );
@@ -132,11 +233,53 @@ mod tests {
             do_split_paths(
                 &OsString::from("/good/foo'bar:/bad/foo"),
                 Path::new("/bad"),
-                true
+                true,
+                &PathFilter::default()
             ),
             OsString::from(
                 "export PATH='/good/foo'\"'\"'bar:/bad/foo'\nexport WSLPATH='/bad/foo'\n"
             )
         );
     }
+
+    #[test]
+    fn removes_duplicates() {
+        assert_eq!(
+            do_split_paths(
+                &OsString::from("/good/foo:/bad/Foo:/good/foo:/bad/foo/"),
+                Path::new("/bad"),
+                true,
+                &PathFilter::default()
+            ),
+            OsString::from("export PATH='/good/foo:/bad/Foo'\nexport WSLPATH='/bad/Foo'\n")
+        );
+    }
+
+    #[test]
+    fn filters_interop() {
+        let filter = PathFilter::parse(
+            "# keep the basics\nallow /bad/Windows/*\nallow /bad/Users/*/bin\ndeny /bad/windows/system32/openssh\n",
+        )
+        .unwrap();
+        assert_eq!(
+            do_split_paths(
+                &OsString::from(
+                    "/good/foo:/bad/Windows/system32:/bad/Windows/System32/OpenSSH/:/bad/Users/me/bin:/bad/Program Files/Git/cmd"
+                ),
+                Path::new("/bad"),
+                true,
+                &filter
+            ),
+            OsString::from(
+                "export PATH='/good/foo:/bad/Windows/system32:/bad/Users/me/bin'\nexport WSLPATH='/bad/Windows/system32:/bad/Windows/System32/OpenSSH/:/bad/Users/me/bin:/bad/Program Files/Git/cmd'\n"
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        assert!(PathFilter::parse("keep /bad/foo\n").is_err());
+        assert!(PathFilter::parse("deny\n").is_err());
+        assert_eq!(PathFilter::parse("").unwrap(), PathFilter::default());
+    }
 }
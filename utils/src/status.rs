@@ -0,0 +1,248 @@
+#[cfg(test)]
+mod test_util;
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use std::fmt::Write;
+use std::fs::read_to_string;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+use nixos_wsl_utils::interop::{live_sockets, SOCKET_DIR};
+use nixos_wsl_utils::quote::json_string;
+
+/// Reports whether the distro finished booting, which units failed, and whether interop and WSLg work.
+/// Meant for scripts on the Windows side, e.g. `wsl.exe -d NixOS -- nixos-wsl-status --wait=60 --json`.
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-status", version = build_info::VERSION)]
+struct Args {
+    /// Print one JSON object instead of text
+    #[arg(long)]
+    json: bool,
+
+    /// Wait up to this many seconds for the boot to finish, and fail if it doesn't
+    #[arg(long)]
+    wait: Option<u64>,
+
+    #[arg(long, default_value = "systemctl")]
+    systemctl: PathBuf,
+
+    /// Where WSL mounts the WSLg system distro
+    #[arg(long, default_value = "/mnt/wslg")]
+    wslg_dir: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+struct Status {
+    /// What `systemctl is-system-running` says, e.g. `starting`, `running` or `degraded`
+    state: String,
+    default_target: bool,
+    failed_units: Vec<String>,
+    interop: bool,
+    wslg: bool,
+}
+
+impl Status {
+    /// Booting is over once default.target is reached, even if some units failed on the way
+    fn ready(&self) -> bool {
+        self.default_target && matches!(self.state.as_str(), "running" | "degraded")
+    }
+
+    fn format_text(&self) -> String {
+        let yes_no = |b: bool| if b { "yes" } else { "no" };
+        let mut result = String::new();
+        let _ = writeln!(result, "state: {}", self.state);
+        let _ = writeln!(result, "ready: {}", yes_no(self.ready()));
+        let _ = writeln!(
+            result,
+            "failed units: {}",
+            if self.failed_units.is_empty() {
+                "none".to_string()
+            } else {
+                self.failed_units.join(" ")
+            }
+        );
+        let _ = writeln!(result, "interop: {}", yes_no(self.interop));
+        let _ = writeln!(result, "wslg: {}", yes_no(self.wslg));
+        result
+    }
+
+    fn format_json(&self) -> String {
+        let failed: Vec<String> = self.failed_units.iter().map(|u| json_string(u)).collect();
+        format!(
+            "{{\"state\":{},\"ready\":{},\"default_target\":{},\"failed_units\":[{}],\"interop\":{},\"wslg\":{}}}",
+            json_string(&self.state),
+            self.ready(),
+            self.default_target,
+            failed.join(","),
+            self.interop,
+            self.wslg
+        )
+    }
+}
+
+/// Runs systemctl and returns what it printed. Exit codes are ignored, since the
+/// `is-*` commands fail to say "no".
+fn systemctl(systemctl: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let output = Command::new(systemctl)
+        .args(args)
+        .output()
+        .with_context(|| format!("When running {} {}", systemctl.display(), args.join(" ")))?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The unit names in `systemctl list-units --plain --no-legend` output
+fn parse_units(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(String::from)
+        .collect()
+}
+
+/// WSL registers .exe files with binfmt_misc, and needs a live socket to start them
+fn interop_works(binfmt: &Path, sockets: &Path) -> bool {
+    let enabled = read_to_string(binfmt).map_or(false, |s| s.lines().next() == Some("enabled"));
+    let socket = live_sockets(sockets).map_or(false, |live| !live.is_empty());
+    enabled && socket
+}
+
+fn wslg_works(wslg_dir: &Path) -> bool {
+    [".X11-unix/X0", "runtime-dir/wayland-0"]
+        .iter()
+        .all(|socket| UnixStream::connect(wslg_dir.join(socket)).is_ok())
+}
+
+fn status(args: &Args) -> anyhow::Result<Status> {
+    let mut state = systemctl(&args.systemctl, &["is-system-running"])?
+        .trim()
+        .to_string();
+    // Before systemd listens on its socket, or in a distro that doesn't run it
+    if state.is_empty() {
+        state = "offline".to_string();
+    }
+    let default_target =
+        systemctl(&args.systemctl, &["is-active", "default.target"])?.trim() == "active";
+    let failed_units = parse_units(&systemctl(
+        &args.systemctl,
+        &[
+            "list-units",
+            "--state=failed",
+            "--plain",
+            "--no-legend",
+            "--no-pager",
+        ],
+    )?);
+    Ok(Status {
+        state,
+        default_target,
+        failed_units,
+        interop: interop_works(
+            Path::new("/proc/sys/fs/binfmt_misc/WSLInterop"),
+            Path::new(SOCKET_DIR),
+        ),
+        wslg: wslg_works(&args.wslg_dir),
+    })
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    let deadline = args
+        .wait
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+
+    let mut current = status(&args)?;
+    while let Some(deadline) = deadline {
+        if current.ready() || Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(Duration::from_millis(500));
+        current = status(&args)?;
+    }
+
+    if args.json {
+        println!("{}", current.format_json());
+    } else {
+        print!("{}", current.format_text());
+    }
+    if args.wait.is_some() && !current.ready() {
+        bail!("The boot didn't finish in time");
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::fs::{remove_dir_all, write};
+    use std::os::unix::net::UnixListener;
+
+    fn status(state: &str, default_target: bool) -> Status {
+        Status {
+            state: state.to_string(),
+            default_target,
+            failed_units: vec!["a.service".to_string(), "b\"c.mount".to_string()],
+            interop: true,
+            wslg: false,
+        }
+    }
+
+    #[test]
+    fn ready_after_default_target() {
+        assert!(status("degraded", true).ready());
+        assert!(!status("starting", false).ready());
+        assert!(!status("stopping", true).ready());
+    }
+
+    #[test]
+    fn formats_status() {
+        assert_eq!(
+            status("degraded", true).format_json(),
+            r#"{"state":"degraded","ready":true,"default_target":true,"failed_units":["a.service","b\"c.mount"],"interop":true,"wslg":false}"#
+        );
+        assert_eq!(
+            status("starting", false).format_text(),
+            "state: starting\nready: no\nfailed units: a.service b\"c.mount\ninterop: yes\nwslg: no\n"
+        );
+    }
+
+    #[test]
+    fn parses_failed_units() {
+        let output = "nix-daemon.service loaded failed failed Nix Daemon\n\
+                      mnt-d.mount       loaded failed failed /mnt/d\n";
+        assert_eq!(
+            parse_units(output),
+            vec!["nix-daemon.service", "mnt-d.mount"]
+        );
+        assert!(parse_units("").is_empty());
+    }
+
+    #[test]
+    fn checks_interop() {
+        let dir = scratch_dir("status-interop");
+        let binfmt = dir.join("WSLInterop");
+        write(&binfmt, "enabled\ninterpreter /init\n").unwrap();
+        // Left behind by a session that is gone
+        write(dir.join("1_interop"), "").unwrap();
+        assert!(!interop_works(&binfmt, &dir));
+
+        let _listener = UnixListener::bind(dir.join("2_interop")).unwrap();
+        assert!(interop_works(&binfmt, &dir));
+        write(&binfmt, "disabled\n").unwrap();
+        assert!(!interop_works(&binfmt, &dir));
+        remove_dir_all(dir).unwrap();
+    }
+}
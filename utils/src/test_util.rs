@@ -0,0 +1,11 @@
+use std::env;
+use std::fs::{create_dir_all, remove_dir_all};
+use std::path::PathBuf;
+
+/// An empty directory under the temp dir, unique to `name` and this test run
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("nixos-wsl-{}-{}", name, std::process::id()));
+    let _ = remove_dir_all(&dir);
+    create_dir_all(&dir).unwrap();
+    dir
+}
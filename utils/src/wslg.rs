@@ -0,0 +1,145 @@
+#[cfg(test)]
+mod test_util;
+
+use anyhow::Context;
+use clap::Parser;
+use nix::mount::{mount, MsFlags};
+use std::fs::{create_dir_all, read_to_string, remove_file, symlink_metadata};
+use std::path::{Path, PathBuf};
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+use nixos_wsl_utils::mountinfo::MountInfo;
+
+/// Makes the WSLg X11 sockets available in /tmp again, after systemd mounted a fresh tmpfs on it
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-wslg", version = build_info::VERSION)]
+struct Args {
+    /// Where WSL mounts the WSLg system distro
+    #[arg(long, default_value = "/mnt/wslg")]
+    wslg_dir: PathBuf,
+
+    #[arg(long, default_value = "/tmp/.X11-unix")]
+    x11_dir: PathBuf,
+}
+
+/// What has to happen to get the WSLg sockets into `x11_dir`
+#[derive(Debug, PartialEq)]
+enum Plan {
+    /// WSLg is turned off in .wslconfig, or this WSL doesn't have it
+    NoWslg,
+    AlreadyMounted,
+    /// Remove the symlink that older NixOS-WSL versions or WSL itself created, then mount
+    ReplaceSymlink,
+    Mount,
+}
+
+fn plan(wslg_x11: &Path, x11_dir: &Path, mountinfo: &str) -> Plan {
+    if !wslg_x11.is_dir() {
+        Plan::NoWslg
+    } else if MountInfo::parse(mountinfo).is_mountpoint(x11_dir) {
+        Plan::AlreadyMounted
+    } else if symlink_metadata(x11_dir).map_or(false, |m| m.is_symlink()) {
+        Plan::ReplaceSymlink
+    } else {
+        Plan::Mount
+    }
+}
+
+fn bind_read_only(source: &Path, target: &Path) -> anyhow::Result<()> {
+    mount(
+        Some(source),
+        target,
+        None::<&str>,
+        MsFlags::MS_BIND,
+        None::<&str>,
+    )
+    .with_context(|| {
+        format!(
+            "When bind mounting {} on {}",
+            source.display(),
+            target.display()
+        )
+    })?;
+    // Read-only, so nothing in the distro can clobber the sockets again
+    mount(
+        None::<&str>,
+        target,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+        None::<&str>,
+    )
+    .with_context(|| format!("When remounting {} read-only", target.display()))
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    let wslg_x11 = args.wslg_dir.join(".X11-unix");
+    let mountinfo =
+        read_to_string("/proc/self/mountinfo").context("When reading /proc/self/mountinfo")?;
+
+    match plan(&wslg_x11, &args.x11_dir, &mountinfo) {
+        Plan::NoWslg => {
+            println!("{} does not exist, WSLg is not running", wslg_x11.display());
+            return Ok(());
+        }
+        Plan::AlreadyMounted => {
+            println!("{} is already mounted", args.x11_dir.display());
+            return Ok(());
+        }
+        Plan::ReplaceSymlink => remove_file(&args.x11_dir)
+            .with_context(|| format!("When removing {}", args.x11_dir.display()))?,
+        Plan::Mount => {}
+    }
+
+    create_dir_all(&args.x11_dir)
+        .with_context(|| format!("When creating {}", args.x11_dir.display()))?;
+    bind_read_only(&wslg_x11, &args.x11_dir)?;
+    println!(
+        "Mounted {} on {}",
+        wslg_x11.display(),
+        args.x11_dir.display()
+    );
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch_dir;
+    use std::fs::{create_dir_all, remove_dir_all};
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn finds_escaped_mountpoints() {
+        let mountinfo = "40 22 0:40 / /tmp/my\\040dir ro - tmpfs none rw\n";
+        let mounts = MountInfo::parse(mountinfo);
+        assert!(mounts.is_mountpoint(Path::new("/tmp/my dir")));
+        assert!(!mounts.is_mountpoint(Path::new("/tmp")));
+    }
+
+    #[test]
+    fn plans_mount() {
+        let dir = scratch_dir("wslg");
+        let (wslg_x11, x11_dir) = (dir.join("wslg/.X11-unix"), dir.join("tmp/.X11-unix"));
+        assert_eq!(plan(&wslg_x11, &x11_dir, ""), Plan::NoWslg);
+
+        create_dir_all(&wslg_x11).unwrap();
+        assert_eq!(plan(&wslg_x11, &x11_dir, ""), Plan::Mount);
+
+        create_dir_all(dir.join("tmp")).unwrap();
+        symlink(&wslg_x11, &x11_dir).unwrap();
+        assert_eq!(plan(&wslg_x11, &x11_dir, ""), Plan::ReplaceSymlink);
+
+        let mountinfo = format!("50 22 0:41 / {} ro - 9p none rw\n", x11_dir.display());
+        assert_eq!(plan(&wslg_x11, &x11_dir, &mountinfo), Plan::AlreadyMounted);
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+use anyhow::{anyhow, bail, Context};
+use clap::Parser;
+use std::env;
+use std::fs::read_to_string;
+use std::path::{Component, Path, PathBuf};
+
+use nixos_wsl_utils::build_info::{self, handle_json_version};
+use nixos_wsl_utils::mountinfo::unescape_mountinfo_path;
+
+/// Converts paths between Windows and WSL, like wslpath, but without going through interop
+#[derive(Parser, Debug)]
+#[command(name = "nixos-wsl-path", version = build_info::VERSION)]
+struct Args {
+    /// Translate from a WSL path to a Windows path, with backslashes
+    #[arg(short = 'w', conflicts_with_all = ["mixed", "unix"])]
+    windows: bool,
+
+    /// Translate from a WSL path to a Windows path, with forward slashes
+    #[arg(short = 'm', conflicts_with = "unix")]
+    mixed: bool,
+
+    /// Translate from a Windows path to a WSL path (the default)
+    #[arg(short = 'u')]
+    unix: bool,
+
+    /// Force the result to be an absolute path
+    #[arg(short = 'a')]
+    absolute: bool,
+
+    path: String,
+}
+
+/// A Windows drive or share mounted into the distro
+#[derive(Debug, PartialEq)]
+struct DrvfsMount {
+    /// e.g. `C:` or `\\server\share`, without a trailing backslash
+    windows: String,
+    linux: PathBuf,
+}
+
+/// Finds drvfs mounts in /proc/mounts. On WSL 2 they are 9p or virtiofs mounts with the Windows path
+/// in the `path=` option, on WSL 1 drvfs mounts with the Windows path as the source.
+fn parse_mounts(mounts: &str) -> Vec<DrvfsMount> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let (source, target, fstype, options) = (
+                fields.first()?,
+                fields.get(1)?,
+                fields.get(2)?,
+                fields.get(3)?,
+            );
+            let options = unescape_mountinfo_path(options);
+            let windows = match *fstype {
+                "drvfs" => unescape_mountinfo_path(source),
+                "9p" | "virtiofs" => options
+                    .split([',', ';'])
+                    .find_map(|option| option.strip_prefix("path="))?
+                    .to_string(),
+                _ => return None,
+            };
+            Some(DrvfsMount {
+                windows: windows.trim_end_matches('\\').to_string(),
+                linux: PathBuf::from(unescape_mountinfo_path(target)),
+            })
+        })
+        .collect()
+}
+
+/// Strips `prefix` from `path` if it matches case-insensitively, up to a separator
+fn strip_windows_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = path.get(..prefix.len())?;
+    let rest = &path[prefix.len()..];
+    (head.eq_ignore_ascii_case(prefix) && (rest.is_empty() || rest.starts_with('\\')))
+        .then_some(rest)
+}
+
+fn join_linux(root: &Path, rest: &str) -> PathBuf {
+    let mut path = root.to_path_buf();
+    path.extend(rest.split('\\').filter(|part| !part.is_empty()));
+    path
+}
+
+fn to_linux(path: &str, mounts: &[DrvfsMount], distro: Option<&str>) -> anyhow::Result<PathBuf> {
+    let path = path.replace('/', "\\");
+
+    // Paths into this distro, e.g. \\wsl.localhost\NixOS\etc
+    for host in ["\\\\wsl.localhost\\", "\\\\wsl$\\"] {
+        if let Some(rest) = strip_windows_prefix(&path, host.trim_end_matches('\\')) {
+            let rest = rest.trim_start_matches('\\');
+            let (name, rest) = rest.split_once('\\').unwrap_or((rest, ""));
+            if distro.map_or(false, |distro| distro.eq_ignore_ascii_case(name)) {
+                return Ok(join_linux(Path::new("/"), rest));
+            }
+            bail!("{} is in another distro", path);
+        }
+    }
+
+    // Longest prefix first, so shares mounted below a drive win
+    let mut candidates: Vec<&DrvfsMount> = mounts.iter().collect();
+    candidates.sort_by_key(|mount| std::cmp::Reverse(mount.windows.len()));
+    for mount in candidates {
+        if let Some(rest) = strip_windows_prefix(&path, &mount.windows) {
+            return Ok(join_linux(&mount.linux, rest));
+        }
+    }
+
+    let is_absolute = path.starts_with("\\\\") || path.get(1..2) == Some(":");
+    if is_absolute {
+        bail!("{} is not on a mounted drive", path);
+    }
+    Ok(PathBuf::from(path.replace('\\', "/")))
+}
+
+fn to_windows(path: &Path, mounts: &[DrvfsMount], distro: Option<&str>) -> anyhow::Result<String> {
+    let components = || {
+        path.components().filter_map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+    };
+
+    if path.is_relative() {
+        return Ok(components().collect::<Vec<_>>().join("\\"));
+    }
+
+    // Longest prefix first, so mounts below other mounts win
+    let mut candidates: Vec<&DrvfsMount> = mounts.iter().collect();
+    candidates.sort_by_key(|mount| std::cmp::Reverse(mount.linux.components().count()));
+    for mount in candidates {
+        if let Ok(rest) = path.strip_prefix(&mount.linux) {
+            let mut result = mount.windows.clone();
+            result.push('\\');
+            let rest: Vec<&str> = rest.iter().filter_map(|part| part.to_str()).collect();
+            result.push_str(&rest.join("\\"));
+            return Ok(result);
+        }
+    }
+
+    let distro = distro.ok_or(anyhow!(
+        "{} is not on a Windows drive and WSL_DISTRO_NAME is not set",
+        path.display()
+    ))?;
+    let mut result = format!("\\\\wsl.localhost\\{}", distro);
+    for part in components() {
+        result.push('\\');
+        result.push_str(part);
+    }
+    Ok(result)
+}
+
+fn real_main() -> anyhow::Result<()> {
+    handle_json_version(env!("CARGO_BIN_NAME"));
+    let args = Args::parse();
+    let mounts =
+        parse_mounts(&read_to_string("/proc/mounts").context("When reading /proc/mounts")?);
+    let distro = env::var("WSL_DISTRO_NAME").ok();
+
+    if args.windows || args.mixed {
+        let mut path = PathBuf::from(&args.path);
+        if args.absolute && path.is_relative() {
+            path = env::current_dir()
+                .context("When getting the working directory")?
+                .join(path);
+        }
+        let result = to_windows(&path, &mounts, distro.as_deref())?;
+        if args.mixed {
+            println!("{}", result.replace('\\', "/"));
+        } else {
+            println!("{}", result);
+        }
+    } else {
+        let mut path = to_linux(&args.path, &mounts, distro.as_deref())?;
+        if args.absolute && path.is_relative() {
+            path = env::current_dir()
+                .context("When getting the working directory")?
+                .join(path);
+        }
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = real_main() {
+        eprintln!("Error: {:?}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTS: &str = "\
+/dev/sdc / ext4 rw,relatime 0 0
+C:\\134 /mnt/c 9p rw,noatime,dirsync,aname=drvfs;path=C:\\;uid=1000;gid=100 0 0
+drvfs /mnt/d 9p rw,noatime,aname=drvfs;path=D:\\;uid=1000 0 0
+\\134\\134server\\134share /mnt/my\\040share 9p rw,aname=drvfs;path=\\\\server\\share 0 0
+E:\\134 /mnt/e drvfs rw,noatime 0 0
+";
+
+    fn mounts() -> Vec<DrvfsMount> {
+        parse_mounts(MOUNTS)
+    }
+
+    #[test]
+    fn parses_drvfs_mounts() {
+        assert_eq!(
+            mounts(),
+            vec![
+                DrvfsMount {
+                    windows: "C:".to_string(),
+                    linux: PathBuf::from("/mnt/c")
+                },
+                DrvfsMount {
+                    windows: "D:".to_string(),
+                    linux: PathBuf::from("/mnt/d")
+                },
+                DrvfsMount {
+                    windows: "\\\\server\\share".to_string(),
+                    linux: PathBuf::from("/mnt/my share")
+                },
+                DrvfsMount {
+                    windows: "E:".to_string(),
+                    linux: PathBuf::from("/mnt/e")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn converts_windows_paths() {
+        let mounts = mounts();
+        let convert = |path| to_linux(path, &mounts, Some("NixOS")).unwrap();
+        assert_eq!(convert("C:\\Users\\me"), PathBuf::from("/mnt/c/Users/me"));
+        assert_eq!(convert("c:/Users/me/"), PathBuf::from("/mnt/c/Users/me"));
+        assert_eq!(convert("D:"), PathBuf::from("/mnt/d"));
+        assert_eq!(
+            convert("\\\\SERVER\\share\\x"),
+            PathBuf::from("/mnt/my share/x")
+        );
+        assert_eq!(
+            convert("\\\\wsl.localhost\\nixos\\etc\\nixos"),
+            PathBuf::from("/etc/nixos")
+        );
+        assert_eq!(convert("\\\\wsl$\\NixOS"), PathBuf::from("/"));
+        assert_eq!(convert("foo\\bar"), PathBuf::from("foo/bar"));
+    }
+
+    #[test]
+    fn rejects_unmounted_windows_paths() {
+        let mounts = mounts();
+        assert!(to_linux("Z:\\foo", &mounts, Some("NixOS")).is_err());
+        assert!(to_linux("\\\\wsl.localhost\\Ubuntu\\etc", &mounts, Some("NixOS")).is_err());
+        // Not a prefix match
+        assert!(to_linux("\\\\server\\shared", &mounts, Some("NixOS")).is_err());
+    }
+
+    #[test]
+    fn converts_wsl_paths() {
+        let mounts = mounts();
+        let convert = |path: &str| to_windows(Path::new(path), &mounts, Some("NixOS")).unwrap();
+        assert_eq!(convert("/mnt/c/Users/me"), "C:\\Users\\me");
+        assert_eq!(convert("/mnt/c"), "C:\\");
+        assert_eq!(convert("/mnt/my share/x"), "\\\\server\\share\\x");
+        assert_eq!(
+            convert("/etc/nixos"),
+            "\\\\wsl.localhost\\NixOS\\etc\\nixos"
+        );
+        assert_eq!(convert("foo/bar"), "foo\\bar");
+        assert!(to_windows(Path::new("/etc"), &mounts, None).is_err());
+    }
+}